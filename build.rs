@@ -0,0 +1,33 @@
+use std::{path::Path, process::Command, time::{SystemTime, UNIX_EPOCH}};
+
+#[path = "src/timestamp.rs"]
+mod timestamp;
+
+use timestamp::format_utc;
+
+fn main() {
+    let git_commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+
+    println!("cargo:rustc-env=PORCELET_GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=PORCELET_BUILD_TIMESTAMP={}", format_utc(timestamp));
+    // Cargo reruns the script on every build while a watched path is
+    // missing, so only the git files that exist are watched, and outside a
+    // checkout only the script itself. `git gc` moves refs into packed-refs.
+    let git_files: Vec<&str> = [".git/HEAD", ".git/refs", ".git/packed-refs"].into_iter().filter(|path| Path::new(path).exists()).collect();
+    if git_files.is_empty() {
+        println!("cargo:rerun-if-changed=build.rs");
+    }
+    for path in git_files {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+}
+
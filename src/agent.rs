@@ -1,11 +1,538 @@
-use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::{future::Future, net::{Ipv4Addr, SocketAddr}, path::PathBuf, pin::Pin, sync::{Arc, Mutex, OnceLock, atomic::{AtomicU64, Ordering}}, task::Poll, time::Duration};
 
-use tokio::{net::windows::named_pipe::{ServerOptions, ClientOptions}, io::{AsyncWriteExt, AsyncReadExt}, sync::mpsc};
+use anyhow::Context;
+use thiserror::Error;
+use tracing::Instrument;
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, net::{TcpListener, TcpStream, windows::named_pipe::{ServerOptions, ClientOptions, NamedPipeClient, NamedPipeServer}}, sync::{mpsc, watch}, task::JoinHandle};
+
+use crate::{config::AgentConfig, metrics::AgentMetrics, protocol::{self, AgentRequest, AgentResponse, Capabilities, ProtocolError, StateDump, VersionInfo}, rate_limit::RateLimiter, session::{SessionRegistry, SessionInfo}, win32};
+
+/// Errors returned by the client side of the agent protocol.
+/// 
+/// Transport errors mean the agent could not be reached, protocol errors
+/// mean the exchange with it broke down, and agent errors mean it handled
+/// the request and rejected it.
+#[derive(Error, Debug)]
+pub enum ClientError {
+    /// The pipe does not exist, so no agent is running.
+    #[error("agent is not running")]
+    AgentNotRunning,
+
+    /// The pipe exists but its ACL does not allow this user.
+    #[error("access to the agent pipe was denied")]
+    AccessDenied,
+
+    /// Connecting to or talking over the pipe failed, `context` describes
+    /// what was attempted.
+    #[error("{context}")]
+    Transport {
+        context: &'static str,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The agent was reached but the exchange did not follow the protocol.
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+
+    /// The agent handled the request and returned an error.
+    #[error("agent returned an error: {0}")]
+    Agent(String),
+
+    /// The agent does not support the request, it is likely older than the
+    /// client.
+    #[error("the running agent does not support {0} requests, it may be older than this CLI")]
+    Unsupported(&'static str),
+
+    /// The agent pipe did not start serving in time.
+    #[error("agent was not serving its pipe after {}s", .0.as_secs())]
+    NotReady(Duration),
+
+    /// The agent is shutting down and did not serve the request.
+    #[error("agent is shutting down")]
+    ShuttingDown,
+
+    /// The agent accepted the connection but did not respond in time.
+    #[error("agent did not respond within {:.1}s, it may be shutting down", .0.as_secs_f64())]
+    NotResponding(Duration),
+}
+
+/// Details of the client served by a connection.
+pub struct ConnectionContext {
+    /// Connection ID assigned by the agent.
+    pub id: u64,
+    /// Identity of the client, resolved when it connected.
+    pub client: ClientContext,
+    request_read_timeout: Duration,
+    max_message_size: u32,
+    cancel: CancellationToken,
+}
+
+impl ConnectionContext {
+    /// Wait until the agent starts shutting down. Handlers doing long work
+    /// should stop once this completes.
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Returns true if the client is an elevated administrator process and
+    /// may make privileged requests.
+    pub fn is_admin(&self) -> bool {
+        self.client.elevated
+    }
+
+    /// Returns true if the client executable is in `allowed_images`, or if
+    /// the allowlist is empty.
+    fn is_allowed_client(&self, allowed_images: &[PathBuf]) -> bool {
+        if allowed_images.is_empty() {
+            return true;
+        }
+
+        let image = match &self.client.image_path {
+            Some(image) => image,
+            None => {
+                tracing::warn!("Could not resolve the client executable");
+                return false;
+            },
+        };
+        let image = image.to_string_lossy();
+        let allowed = allowed_images.iter().any(|allowed| allowed.to_string_lossy().eq_ignore_ascii_case(&image));
+        if !allowed {
+            tracing::warn!(image = %image, "Client executable is not in the allowlist");
+        }
+        allowed
+    }
+}
+
+/// Identity of a client, resolved from its process when it connects.
+/// 
+/// Fields that could not be resolved, for example because the client
+/// exited, are `None`, and the client is not considered elevated.
+#[derive(Clone, Default, Debug)]
+pub struct ClientContext {
+    /// Transport the client connected over.
+    pub transport: Transport,
+    /// Process ID of the client.
+    pub pid: Option<u32>,
+    /// User SID of the client process, such as `S-1-5-18`.
+    pub sid: Option<String>,
+    /// Full path of the client executable.
+    pub image_path: Option<PathBuf>,
+    /// Whether the client process is running elevated.
+    pub elevated: bool,
+}
+
+/// Transports clients connect to the agent over.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Transport {
+    /// The agent named pipe.
+    #[default]
+    Pipe,
+    /// Loopback TCP, see `AgentConfig::tcp_port`.
+    Tcp,
+}
+
+impl ClientContext {
+    /// Resolve the identity of the client process `pid`, connected over
+    /// `transport`.
+    pub fn resolve(pid: Option<u32>, transport: Transport) -> Self {
+        let pid = match pid {
+            Some(pid) => pid,
+            None => return Self { transport, ..Self::default() },
+        };
+        let log_failure = |what: &str, err: std::io::Error| {
+            tracing::debug!(error = %err, "Could not resolve the client {}", what);
+        };
+        Self {
+            transport,
+            pid: Some(pid),
+            sid: win32::process_user_sid(pid).map_err(|err| log_failure("SID", err)).ok(),
+            image_path: win32::process_image_path(pid).map_err(|err| log_failure("executable", err)).ok(),
+            elevated: win32::process_is_elevated(pid).unwrap_or(false),
+        }
+    }
+}
+
+/// Reasons an `Authenticator` refuses a client.
+#[derive(Error, Debug)]
+pub enum AuthError {
+    /// The client identity could not be resolved, so it can't be checked.
+    #[error("client identity could not be determined")]
+    UnknownClient,
+
+    /// The client is known but not allowed.
+    #[error("client is not allowed: {0}")]
+    Denied(String),
+}
+
+/// Decides which clients may make which requests.
+/// 
+/// The agent checks the client of a connection before handling each of its
+/// requests, answering refused requests with an error and disconnecting.
+/// The authenticator is `DefaultAuthenticator` unless another is set with
+/// `Agent::with_authenticator`. The executable allowlist from the
+/// configuration is checked separately, when the client connects.
+pub trait Authenticator: Send + Sync {
+    /// Allow or refuse `request` from the client.
+    fn authorize(&self, client: &ClientContext, request: &AgentRequest) -> Result<(), AuthError>;
+}
+
+/// Built-in authenticator allowing any local user to make read-only
+/// requests, and only elevated administrators, LocalSystem, and the user
+/// the agent itself runs as to make the rest.
+/// 
+/// Clients whose identity can't be resolved are refused, except for
+/// read-only requests over TCP if `allow_unidentified_tcp` is set.
+pub struct DefaultAuthenticator {
+    /// SID of the agent process user, so an agent run in the foreground can
+    /// be used by the user that started it.
+    agent_sid: Option<String>,
+    /// Whether TCP clients whose process can't be resolved may make
+    /// read-only requests.
+    allow_unidentified_tcp: bool,
+}
+
+impl DefaultAuthenticator {
+    /// SID of the LocalSystem account.
+    const LOCAL_SYSTEM_SID: &'static str = "S-1-5-18";
+
+    /// Kinds of the requests that only read the agent state, which any
+    /// local user may make, such as a status query without elevation.
+    const READ_ONLY_REQUESTS: &'static [&'static str] = &["GetCounter", "Version", "Capabilities", "Metrics"];
+
+    /// Let TCP clients whose process can't be resolved, such as tools in
+    /// WSL, make read-only requests, see
+    /// `AgentConfig::tcp_allow_unidentified`.
+    pub fn allow_unidentified_tcp(mut self, allow: bool) -> Self {
+        self.allow_unidentified_tcp = allow;
+        self
+    }
+}
+
+impl Default for DefaultAuthenticator {
+    fn default() -> Self {
+        Self { agent_sid: win32::process_user_sid(std::process::id()).ok(), allow_unidentified_tcp: false }
+    }
+}
+
+impl Authenticator for DefaultAuthenticator {
+    fn authorize(&self, client: &ClientContext, request: &AgentRequest) -> Result<(), AuthError> {
+        if client.elevated {
+            return Ok(());
+        }
+        let read_only = Self::READ_ONLY_REQUESTS.contains(&request.kind());
+        let sid = match client.sid.as_deref() {
+            Some(sid) => sid,
+            None if read_only && client.transport == Transport::Tcp && self.allow_unidentified_tcp => return Ok(()),
+            None => return Err(AuthError::UnknownClient),
+        };
+        if sid == Self::LOCAL_SYSTEM_SID || Some(sid) == self.agent_sid.as_deref() || read_only {
+            return Ok(());
+        }
+        Err(AuthError::Denied(format!("only administrators and LocalSystem may make {} requests", request.kind())))
+    }
+}
+
+/// Cooperative cancellation signal shared by the tasks of a running agent.
+/// 
+/// Clones share the signal. Tasks wait on `cancelled()` and wind down on
+/// their own, rather than being aborted part way through their work.
+#[derive(Clone)]
+pub struct CancellationToken (Arc<watch::Sender<bool>>);
+
+impl CancellationToken {
+    /// Signal every clone of the token.
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Wait until the token is cancelled, returning immediately if it
+    /// already is.
+    pub async fn cancelled(&self) {
+        Self::wait(self.0.subscribe()).await
+    }
+
+    /// Create a token that is cancelled along with this one, but can also
+    /// be cancelled on its own without affecting this one.
+    pub fn child(&self) -> CancellationToken {
+        let child = CancellationToken::default();
+        let child_sender = Arc::downgrade(&child.0);
+        let child_receiver = child.0.subscribe();
+        let parent = self.clone();
+        // Stops once either token is cancelled, or the child is dropped.
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = parent.cancelled() => {
+                    if let Some(child) = child_sender.upgrade() {
+                        child.send_replace(true);
+                    }
+                },
+                _ = Self::wait(child_receiver) => {},
+            }
+        });
+        child
+    }
+
+    /// Wait until `receiver` sees its token cancelled, or every clone of the
+    /// token is dropped.
+    async fn wait(mut receiver: watch::Receiver<bool>) {
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken (Arc::new(watch::channel(false).0))
+    }
+}
+
+/// Cancels a token when dropped, so every exit from `Agent::run` stops its
+/// tasks.
+struct CancelOnDrop (CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Pipe listeners spawned together by `Agent::spawn_listeners`, replaced
+/// together if the pipe has to be recreated.
+struct ListenerGeneration {
+    /// Sequence number, listeners report it when they stop on their own.
+    id: u64,
+    /// Child of the agent token, cancels only these listeners.
+    cancel: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ListenerGeneration {
+    /// Time the listeners get to stop once cancelled before they are
+    /// aborted.
+    const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Cancel the listeners and wait for them to close their pipe
+    /// instances.
+    /// 
+    /// A listener blocked handing over a connection does not see the
+    /// cancellation, so listeners still running after `STOP_TIMEOUT` are
+    /// aborted.
+    async fn stop(&mut self) {
+        self.cancel.cancel();
+        let deadline = tokio::time::Instant::now() + Self::STOP_TIMEOUT;
+        for mut task in self.tasks.drain(..) {
+            if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+                tracing::warn!(generation = self.id, "Pipe listener did not stop in time, aborting it");
+                task.abort();
+            }
+        }
+    }
+}
+
+/// A connected client, over the agent pipe or loopback TCP.
+enum Connection {
+    Pipe (NamedPipeServer),
+    Tcp (TcpStream),
+}
+
+impl Connection {
+    /// Transport the client connected over.
+    fn transport(&self) -> Transport {
+        match self {
+            Connection::Pipe(_) => Transport::Pipe,
+            Connection::Tcp(_) => Transport::Tcp,
+        }
+    }
+
+    /// Process ID of the client, if it could be determined.
+    fn client_pid(&self) -> Option<u32> {
+        match self {
+            Connection::Pipe(server) => win32::named_pipe_client_process_id(server).ok(),
+            Connection::Tcp(stream) => match (stream.peer_addr(), stream.local_addr()) {
+                (Ok(SocketAddr::V4(client)), Ok(SocketAddr::V4(server))) => win32::tcp_client_process_id(client, server).ok(),
+                _ => None,
+            },
+        }
+    }
+
+    /// Disconnect the client. TCP connections are closed once dropped.
+    fn disconnect(&self) -> std::io::Result<()> {
+        match self {
+            Connection::Pipe(server) => server.disconnect(),
+            Connection::Tcp(_) => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Pipe(server) => Pin::new(server).poll_read(cx, buf),
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Pipe(server) => Pin::new(server).poll_write(cx, buf),
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Pipe(server) => Pin::new(server).poll_flush(cx),
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Pipe(server) => Pin::new(server).poll_shutdown(cx),
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Agent state shared with the tasks serving connections.
+pub struct AgentState {
+    counter: AtomicU64,
+    metrics: AgentMetrics,
+    sessions: Arc<SessionRegistry>,
+    allowed_client_images: Vec<PathBuf>,
+    /// Configuration the agent is running with, updated on reload.
+    config: Mutex<AgentConfig>,
+}
+
+/// Response future returned by a `RequestHandler`.
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = AgentResponse> + Send + 'a>>;
+
+/// Handles the requests clients send to the agent.
+/// 
+/// The agent dispatches each parsed request to its handler, which is
+/// `DefaultRequestHandler` unless another is passed to `Agent::with_handler`.
+/// The response must be ready within the request deadline.
+pub trait RequestHandler: Send + Sync {
+    /// Handle a single client request.
+    fn handle<'a>(&'a self, request: AgentRequest, context: &'a ConnectionContext, state: &'a AgentState) -> HandlerFuture<'a>;
+}
+
+/// Built-in request handler serving the counter, metrics, and sessions.
+pub struct DefaultRequestHandler;
+
+impl RequestHandler for DefaultRequestHandler {
+    fn handle<'a>(&'a self, request: AgentRequest, context: &'a ConnectionContext, state: &'a AgentState) -> HandlerFuture<'a> {
+        Box::pin(async move { Self::handle_request(request, context, state) })
+    }
+}
+
+impl DefaultRequestHandler {
+    /// Handle a single client request.
+    fn handle_request(request: AgentRequest, context: &ConnectionContext, state: &AgentState) -> AgentResponse {
+        match request {
+            AgentRequest::GetCounter => AgentResponse::Counter(state.counter.load(Ordering::SeqCst)),
+            AgentRequest::NextCounter => AgentResponse::Counter(state.counter.fetch_add(1, Ordering::SeqCst)),
+            AgentRequest::Metrics => AgentResponse::Metrics(state.metrics.render(state.counter.load(Ordering::SeqCst), state.sessions.len())),
+            AgentRequest::Version => AgentResponse::VersionInfo(VersionInfo::current()),
+            AgentRequest::ListSessions => AgentResponse::Sessions(state.sessions.list()),
+            AgentRequest::KillSession { id } => {
+                if id == context.id {
+                    AgentResponse::Error("a session cannot kill itself".to_string())
+                } else if state.sessions.kill(id) {
+                    tracing::info!(session = id, "Killed session");
+                    AgentResponse::SessionKilled(id)
+                } else {
+                    AgentResponse::Error(format!("no session with ID {}", id))
+                }
+            },
+            AgentRequest::ResetCounter => {
+                if !context.is_admin() {
+                    return AgentResponse::Error("resetting the counter requires an elevated client".to_string());
+                }
+                let previous = state.counter.swap(0, Ordering::SeqCst);
+                tracing::info!(previous, "Counter reset");
+                AgentResponse::CounterReset { previous }
+            },
+            AgentRequest::GetConfig => AgentResponse::Config(state.config.lock().unwrap().redacted()),
+            AgentRequest::DumpState => {
+                if !context.is_admin() {
+                    return AgentResponse::Error("dumping the agent state requires an elevated client".to_string());
+                }
+                AgentResponse::StateDump(StateDump {
+                    counter: state.counter.load(Ordering::SeqCst),
+                    uptime_seconds: state.metrics.uptime().as_secs(),
+                    connections_total: state.metrics.connections_total.load(Ordering::Relaxed),
+                    requests_total: state.metrics.requests_total.load(Ordering::Relaxed),
+                    request_errors_total: state.metrics.request_errors_total.load(Ordering::Relaxed),
+                    heartbeat_failures_total: state.metrics.heartbeat_failures_total.load(Ordering::Relaxed),
+                    sessions: state.sessions.list(),
+                    config: state.config.lock().unwrap().redacted(),
+                })
+            },
+            AgentRequest::Capabilities => AgentResponse::Capabilities(Capabilities {
+                requests: AgentRequest::KINDS.iter().map(|kind| kind.to_string()).collect(),
+                features: Vec::new(),
+                endpoints: state.config.lock().unwrap().endpoints(),
+            }),
+            AgentRequest::ReserveCounter { count } => {
+                if count == 0 {
+                    return AgentResponse::Error("must reserve at least one counter value".to_string());
+                }
+                // A single atomic update, so concurrent reservations never
+                // overlap and no values are skipped.
+                match state.counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |counter| counter.checked_add(count)) {
+                    Ok(start) => AgentResponse::CounterRange { start, count },
+                    Err(_) => AgentResponse::Error(format!("reserving {} values would overflow the counter", count)),
+                }
+            },
+        }
+    }
+}
+
+/// Requests a running agent to shut down, see `Agent::shutdown_handle()`.
+#[derive(Clone)]
+pub struct ShutdownHandle (mpsc::Sender<()>);
+
+impl ShutdownHandle {
+    /// Request a shutdown without waiting for it.
+    /// 
+    /// Requests made while a shutdown is already pending, or after the agent
+    /// stopped, are ignored.
+    pub fn request_shutdown(&self) {
+        let _ = self.0.try_send(());
+    }
+}
+
+/// System power transitions forwarded to the agent by the service.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerEvent {
+    /// The system is about to suspend.
+    Suspend,
+    /// The system resumed from suspend.
+    Resume,
+}
+
+/// Agent instance selected with `Agent::select_instance`.
+static INSTANCE: OnceLock<Option<String>> = OnceLock::new();
 
 pub struct Agent {
-    counter: Arc<AtomicU64>,
+    config: AgentConfig,
+    state: Arc<AgentState>,
+    handler: Arc<dyn RequestHandler>,
+    authenticator: Arc<dyn Authenticator>,
+    next_connection_id: u64,
     shutdown_send: mpsc::Sender<()>,
     shutdown_recv: mpsc::Receiver<()>,
+    reload_send: mpsc::Sender<()>,
+    reload_recv: mpsc::Receiver<()>,
+    power_send: mpsc::Sender<PowerEvent>,
+    power_recv: mpsc::Receiver<PowerEvent>,
 }
 
 impl Agent {
@@ -15,54 +542,542 @@ impl Agent {
 
     pub const SERVICE_PIPE: &'static str = r"\\.\pipe\porcelet-agent-socket";
 
+    /// Select the agent instance this process runs or talks to, `None` for
+    /// the default instance.
+    /// 
+    /// Every name derived from the instance, such as `service_name()` and
+    /// `service_pipe()`, must agree for the life of the process, so only
+    /// the first call takes effect. Later calls, and using any of the names
+    /// before the first call, select the default instance for good.
+    /// Returns false if the call did not take effect.
+    pub fn select_instance(instance: Option<String>) -> bool {
+        INSTANCE.set(instance).is_ok()
+    }
+
+    /// Name of the selected agent instance, `None` for the default instance.
+    pub fn instance() -> Option<&'static str> {
+        INSTANCE.get_or_init(|| None).as_deref()
+    }
+
+    /// Service name of the selected instance, `porcelet-agent` or
+    /// `porcelet-agent-<instance>`.
+    pub fn service_name() -> String {
+        match Self::instance() {
+            Some(instance) => format!("{}-{}", Self::SERVICE_NAME, instance),
+            None => Self::SERVICE_NAME.to_string(),
+        }
+    }
+
+    /// Display name of the selected instance's service.
+    pub fn service_display_name() -> String {
+        match Self::instance() {
+            Some(instance) => format!("{} ({})", Self::SERVICE_DISPLAY_NAME, instance),
+            None => Self::SERVICE_DISPLAY_NAME.to_string(),
+        }
+    }
+
+    /// Pipe of the selected instance, `porcelet-agent-socket` or
+    /// `porcelet-agent-<instance>-socket`.
+    pub fn service_pipe() -> String {
+        match Self::instance() {
+            Some(instance) => format!(r"\\.\pipe\porcelet-agent-{}-socket", instance),
+            None => Self::SERVICE_PIPE.to_string(),
+        }
+    }
+
+    /// Data directory of the selected instance, `%ProgramData%\Porcelet`,
+    /// with other instances in its `instances\<instance>` subdirectory.
+    pub fn data_dir() -> PathBuf {
+        let data_dir = std::env::var_os("ProgramData").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(r"C:\ProgramData")).join("Porcelet");
+        match Self::instance() {
+            Some(instance) => data_dir.join("instances").join(instance),
+            None => data_dir,
+        }
+    }
+
+    /// Check that `instance` can be used in a service name, pipe name, and
+    /// path: non-empty, at most 64 characters, and only ASCII letters,
+    /// digits, `-`, and `_`.
+    pub fn validate_instance(instance: &str) -> Result<(), String> {
+        if instance.is_empty() || instance.len() > 64 {
+            return Err("instance names must be 1 to 64 characters long".to_string());
+        }
+        if !instance.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("instance names may only contain ASCII letters, digits, '-', and '_'".to_string());
+        }
+        Ok(())
+    }
+
+    /// Time `query_status` waits for the agent to respond.
+    pub const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Number of times a listener retries creating its next pipe instance
+    /// before giving up and stopping.
+    const PIPE_CREATE_RETRIES: u32 = 5;
+
+    /// Delay before a listener first retries creating its next pipe
+    /// instance, doubled after every failed retry.
+    const PIPE_CREATE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+    /// Delay before the TCP listener accepts again after a failed accept,
+    /// doubled after every further failure up to `TCP_ACCEPT_MAX_DELAY`.
+    const TCP_ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+    /// Longest delay between accepts while they keep failing.
+    const TCP_ACCEPT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+    /// Time spent telling each client connected during shutdown that the
+    /// agent is shutting down.
+    const SHUTDOWN_REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Create an agent using the configuration from the registry.
     pub fn new() -> Self {
+        Self::with_config(AgentConfig::load_or_default())
+    }
+
+    /// Create an agent using the provided configuration.
+    pub fn with_config(config: AgentConfig) -> Self {
+        Self::with_handler(config, Arc::new(DefaultRequestHandler))
+    }
+
+    /// Create an agent that dispatches requests to `handler` instead of the
+    /// built-in handler.
+    pub fn with_handler(config: AgentConfig, handler: Arc<dyn RequestHandler>) -> Self {
         let (shutdown_send, shutdown_recv) = mpsc::channel(1);
+        let (reload_send, reload_recv) = mpsc::channel(1);
+        let (power_send, power_recv) = mpsc::channel(4);
         Self {
-            counter: Arc::new(AtomicU64::new(0)),
+            state: Arc::new(AgentState {
+                counter: AtomicU64::new(0),
+                metrics: AgentMetrics::new(),
+                sessions: Arc::new(SessionRegistry::default()),
+                allowed_client_images: config.allowed_client_images.clone(),
+                config: Mutex::new(config.clone()),
+            }),
+            handler,
+            authenticator: Arc::new(DefaultAuthenticator::default().allow_unidentified_tcp(config.tcp_allow_unidentified)),
+            config,
+            next_connection_id: 0,
             shutdown_send,
             shutdown_recv,
+            reload_send,
+            reload_recv,
+            power_send,
+            power_recv,
+        }
+    }
+
+    /// Check clients with `authenticator` instead of `DefaultAuthenticator`.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Returns a handle that requests the agent to shut down.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle (self.shutdown_send.clone())
+    }
+
+    /// Returns a sender for configuration reload events.
+    /// 
+    /// It is recommended to use try_send with this, and just pass if the
+    /// channel is full, because that means a reload is already pending.
+    pub fn reload_sender(&self) -> mpsc::Sender<()> {
+        self.reload_send.clone()
+    }
+
+    /// Returns a sender for system power events.
+    /// 
+    /// Use try_send with this too, a full channel means events are already
+    /// waiting and the agent only reacts to the latest state.
+    pub fn power_sender(&self) -> mpsc::Sender<PowerEvent> {
+        self.power_send.clone()
+    }
+
+    /// Re-read the configuration and apply it without dropping the pipe.
+    /// 
+    /// Changes take effect for connections accepted after the reload. The
+    /// client allowlist is shared with running connections and is only
+    /// logged as requiring a restart.
+    pub fn reload_config(&mut self) -> std::io::Result<()> {
+        let mut config = AgentConfig::load()?;
+        if config.allowed_client_images != self.config.allowed_client_images {
+            tracing::warn!("Changing the client allowlist requires restart");
+            config.allowed_client_images = self.config.allowed_client_images.clone();
+        }
+        if config.heartbeat_interval != self.config.heartbeat_interval || config.heartbeat_exit_on_failure != self.config.heartbeat_exit_on_failure {
+            tracing::warn!("Changing the heartbeat requires restart");
+            config.heartbeat_interval = self.config.heartbeat_interval;
+            config.heartbeat_exit_on_failure = self.config.heartbeat_exit_on_failure;
+        }
+        if config.listener_count != self.config.listener_count {
+            tracing::warn!("Changing the listener count requires restart");
+            config.listener_count = self.config.listener_count;
+        }
+        if config.tcp_port != self.config.tcp_port || config.tcp_allow_unidentified != self.config.tcp_allow_unidentified {
+            tracing::warn!("Changing the TCP listener requires restart");
+            config.tcp_port = self.config.tcp_port;
+            config.tcp_allow_unidentified = self.config.tcp_allow_unidentified;
+        }
+        if config.log_file != self.config.log_file || config.log_file_max_size != self.config.log_file_max_size || config.log_file_retention != self.config.log_file_retention {
+            tracing::warn!("Changing the log file requires restart");
+            config.log_file = self.config.log_file;
+            config.log_file_max_size = self.config.log_file_max_size;
+            config.log_file_retention = self.config.log_file_retention;
+        }
+        // Never loaded, kept as configured when the agent was created.
+        config.pipe_name = self.config.pipe_name.clone();
+        tracing::info!(?config, "Reloaded configuration");
+        *self.state.config.lock().unwrap() = config.clone();
+        self.config = config;
+        Ok(())
+    }
+
+    /// Create a new server instance of the agent pipe.
+    fn create_pipe_instance(config: &AgentConfig, first_pipe_instance: bool) -> std::io::Result<NamedPipeServer> {
+        ServerOptions::new()
+            .first_pipe_instance(first_pipe_instance)
+            .in_buffer_size(config.in_buffer_size)
+            .out_buffer_size(config.out_buffer_size)
+            .create(config.pipe())
+    }
+
+    /// Wait for clients on a pipe instance, passing each connected instance
+    /// on `connected` and replacing it with a new one.
+    /// 
+    /// New instances use the buffer sizes of the current configuration. If
+    /// one can't be created the connected instance is still passed on, and
+    /// creating a new one is retried with backoff. Only once the retries
+    /// fail too does the listener log the error, report its `generation`
+    /// stopped on `stopped`, and stop. Also stops once `cancel` is
+    /// cancelled or the receiver of `connected` is dropped, closing its
+    /// instance.
+    /// 
+    /// An instance that fails to connect a client is disconnected, or
+    /// replaced if that fails, before it waits for the next client.
+    async fn listen(mut server: NamedPipeServer, state: Arc<AgentState>, connected: mpsc::Sender<Connection>, stopped: mpsc::UnboundedSender<u64>, generation: u64, cancel: CancellationToken) {
+        loop {
+            let result = tokio::select! {
+                result = server.connect() => result,
+                _ = cancel.cancelled() => return,
+            };
+            let next_server = match result {
+                Ok(()) => {
+                    let next_server = {
+                        let config = state.config.lock().unwrap();
+                        Self::create_pipe_instance(&config, false)
+                    };
+                    match next_server {
+                        Ok(next_server) => {
+                            let connected_server = std::mem::replace(&mut server, next_server);
+                            if connected.send(Connection::Pipe(connected_server)).await.is_err() {
+                                return;
+                            }
+                            continue;
+                        },
+                        Err(err) => {
+                            // Serve the client that connected while retrying.
+                            tracing::warn!(error = %err, "Failed to create the next agent pipe instance, retrying");
+                            if connected.send(Connection::Pipe(server)).await.is_err() {
+                                return;
+                            }
+                            Self::retry_create_pipe_instance(&state, &cancel).await
+                        },
+                    }
+                },
+                Err(err) => {
+                    // A client that closed before the connection completed,
+                    // ERROR_NO_DATA, leaves the instance unusable until it is
+                    // disconnected. Connecting it again as is fails at once.
+                    tracing::warn!(error = %err, "Named pipe connection error, resetting the pipe instance");
+                    match server.disconnect() {
+                        Ok(()) => continue,
+                        Err(err) => {
+                            tracing::warn!(error = %err, "Failed to reset the agent pipe instance, replacing it");
+                            Self::retry_create_pipe_instance(&state, &cancel).await
+                        },
+                    }
+                },
+            };
+            server = match next_server {
+                Some(Ok(next_server)) => next_server,
+                Some(Err(err)) => {
+                    tracing::error!(error = %err, "Failed to create the next agent pipe instance, stopping this listener");
+                    let _ = stopped.send(generation);
+                    return;
+                },
+                None => return,
+            };
+        }
+    }
+
+    /// Retry creating a pipe instance after a failure, waiting
+    /// `PIPE_CREATE_RETRY_DELAY` before the first retry and doubling the
+    /// delay after each one.
+    /// 
+    /// Returns the last error after `PIPE_CREATE_RETRIES` failed retries,
+    /// or `None` if `cancel` is cancelled first.
+    async fn retry_create_pipe_instance(state: &AgentState, cancel: &CancellationToken) -> Option<std::io::Result<NamedPipeServer>> {
+        let mut delay = Self::PIPE_CREATE_RETRY_DELAY;
+        for retry in 1..=Self::PIPE_CREATE_RETRIES {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                _ = cancel.cancelled() => return None,
+            }
+            let result = {
+                let config = state.config.lock().unwrap();
+                Self::create_pipe_instance(&config, false)
+            };
+            match result {
+                Ok(server) => {
+                    tracing::info!(retry, "Created the next agent pipe instance");
+                    return Some(Ok(server));
+                },
+                Err(err) if retry < Self::PIPE_CREATE_RETRIES => {
+                    tracing::warn!(error = %err, retry, "Failed to create the next agent pipe instance, retrying");
+                },
+                Err(err) => return Some(Err(err)),
+            }
+            delay *= 2;
+        }
+        unreachable!("the last retry always returns")
+    }
+
+    /// Accept clients on loopback TCP, passing each connection on
+    /// `connected`.
+    /// 
+    /// A failed accept, for example when the process is out of sockets,
+    /// is logged and retried with backoff rather than straight away. Stops
+    /// once `cancel` is cancelled or the receiver of `connected` is dropped.
+    async fn listen_tcp(listener: TcpListener, connected: mpsc::Sender<Connection>, cancel: CancellationToken) {
+        let mut delay = Self::TCP_ACCEPT_RETRY_DELAY;
+        loop {
+            let result = tokio::select! {
+                result = listener.accept() => result,
+                _ = cancel.cancelled() => return,
+            };
+            match result {
+                Ok((stream, _)) => {
+                    delay = Self::TCP_ACCEPT_RETRY_DELAY;
+                    if connected.send(Connection::Tcp(stream)).await.is_err() {
+                        return;
+                    }
+                },
+                Err(err) => {
+                    tracing::error!(error = %err, retry_in = ?delay, "TCP connection error");
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {},
+                        _ = cancel.cancelled() => return,
+                    }
+                    delay = (delay * 2).min(Self::TCP_ACCEPT_MAX_DELAY);
+                },
+            }
+        }
+    }
+
+    /// Create `listener_count` pipe instances, the first one creating the
+    /// pipe, and spawn a listener for each as generation `generation`.
+    /// 
+    /// The listeners stop when `cancel` is cancelled, or when the returned
+    /// generation is stopped.
+    fn spawn_listeners(&self, generation: u64, connected: &mpsc::Sender<Connection>, stopped: &mpsc::UnboundedSender<u64>, cancel: &CancellationToken) -> anyhow::Result<ListenerGeneration> {
+        let listener_count = self.config.listener_count;
+        let mut servers = Vec::with_capacity(listener_count as usize);
+        servers.push(Self::create_pipe_instance(&self.config, true).context("failed to create the agent pipe")?);
+        for _ in 1..listener_count {
+            servers.push(Self::create_pipe_instance(&self.config, false).context("failed to create an agent pipe instance")?);
+        }
+        let cancel = cancel.child();
+        let tasks = servers.into_iter()
+            .map(|server| tokio::spawn(Self::listen(server, self.state.clone(), connected.clone(), stopped.clone(), generation, cancel.clone())))
+            .collect();
+        Ok(ListenerGeneration { id: generation, cancel, tasks })
+    }
+
+    /// Returns true if a live server currently owns the agent pipe.
+    /// 
+    /// This briefly creates and drops a first instance of the pipe without
+    /// serving it, which fails if any server already has an instance open.
+    pub fn pipe_is_owned() -> std::io::Result<bool> {
+        Self::pipe_is_owned_on(&Self::service_pipe())
+    }
+
+    /// Returns true if a live server currently owns `pipe`, like
+    /// `pipe_is_owned`.
+    pub fn pipe_is_owned_on(pipe: &str) -> std::io::Result<bool> {
+        match ServerOptions::new().first_pipe_instance(true).create(pipe) {
+            Ok(_) => Ok(false),
+            // ERROR_ACCESS_DENIED, another server owns the first instance.
+            Err(err) if err.raw_os_error() == Some(5) => Ok(true),
+            Err(err) => Err(err),
         }
     }
 
-    /// Returns a sender for agent shutdown events.
+    /// Answer clients that connected but were not served yet when shutdown
+    /// began with `AgentResponse::ShuttingDown`, so they fail quickly
+    /// instead of waiting on a connection that will just be closed.
+    async fn reject_pending(connected: &mut mpsc::Receiver<Connection>) {
+        while let Ok(connection) = connected.try_recv() {
+            Self::reject(connection).await;
+        }
+    }
+
+    /// Answer a client with `AgentResponse::ShuttingDown` and disconnect it.
+    async fn reject(mut connection: Connection) {
+        let _ = tokio::time::timeout(Self::SHUTDOWN_REJECT_TIMEOUT, protocol::write_message(&mut connection, &AgentResponse::ShuttingDown)).await;
+        let _ = connection.disconnect();
+    }
+
+    /// Periodically check that the agent serves requests on `pipe`.
     /// 
-    /// It is recommended to use try_send with this, and just pass if the channel
-    /// is full or closed because that means a shutdown is already in process.
-    pub fn shutdown_sender(&self) -> mpsc::Sender<()> {
-        self.shutdown_send.clone()
+    /// Failures are counted in the metrics and, if `exit_on_failure` is set,
+    /// reported on `failed` so the agent can exit. Stops once `cancel` is
+    /// cancelled.
+    async fn heartbeat(pipe: String, interval: Duration, exit_on_failure: bool, state: Arc<AgentState>, failed: mpsc::Sender<()>, cancel: CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {},
+                _ = cancel.cancelled() => return,
+            }
+
+            // Version doesn't touch the counter, so the check isn't visible
+            // to clients.
+            let result = tokio::time::timeout(AgentRequest::Version.deadline(), Self::query_version_on(&pipe)).await;
+            let err = match result {
+                Ok(Ok(_)) => continue,
+                Ok(Err(err)) => anyhow::Error::new(err),
+                Err(_) => anyhow::anyhow!("agent did not respond in time"),
+            };
+            tracing::warn!(error = %format_args!("{:#}", err), "Heartbeat check of the agent pipe failed");
+            state.metrics.heartbeat_failures_total.fetch_add(1, Ordering::Relaxed);
+            if exit_on_failure {
+                let _ = failed.try_send(());
+            }
+        }
     }
 
+    /// Serve clients until a shutdown is requested.
+    /// 
+    /// `listener_count` pipe instances listen for clients at the same time,
+    /// all handing their connections to this loop. A listener that can't
+    /// replace its pipe instance stops on its own, and only once every pipe
+    /// listener has stopped does this return an error. The connection rate limit
+    /// delays serving connections rather than accepting them, so up to
+    /// `listener_count` clients may be connected and waiting while throttled.
+    /// If `tcp_port` is configured, clients accepted over loopback TCP are
+    /// served by the same loop.
     pub async fn run(&mut self) -> anyhow::Result<()> {
-        let mut server = ServerOptions::new()
-            .first_pipe_instance(true)
-            .create(Self::SERVICE_PIPE)?;
+        self.config.validate().context("invalid agent configuration")?;
+        let listener_count = self.config.listener_count;
+
+        // Listeners, the heartbeat, and connections all stop cooperatively
+        // once the token is cancelled, which happens however this function
+        // returns.
+        let cancel = CancellationToken::default();
+        let _cancel_on_exit = CancelOnDrop (cancel.clone());
+
+        // The senders are kept to replace the listeners after a resume from
+        // suspend, so the channels never close while this runs. Listeners
+        // that stop on their own report it on `stopped`.
+        let (connected_send, mut connected_recv) = mpsc::channel(listener_count as usize);
+        let (stopped_send, mut stopped_recv) = mpsc::unbounded_channel();
+        let mut listeners = self.spawn_listeners(0, &connected_send, &stopped_send, &cancel)?;
+        let mut live_listeners = listener_count;
+        if let Some(port) = self.config.tcp_port {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await
+                .with_context(|| format!("failed to listen on TCP port {}", port))?;
+            tracing::info!(port, "Serving clients over loopback TCP");
+            tokio::spawn(Self::listen_tcp(listener, connected_send.clone(), cancel.clone()));
+        }
+
+        // A connection over the rate limit is held, along with the time it
+        // may be served, and no other connection is received meanwhile.
+        let mut rate_limiter = RateLimiter::new(self.config.max_connections_per_second);
+        let mut throttled: Option<(Connection, tokio::time::Instant)> = None;
+        let mut rate_limited = false;
+
+        let (heartbeat_failed_send, mut heartbeat_failed_recv) = mpsc::channel(1);
+        if let Some(interval) = self.config.heartbeat_interval {
+            tokio::spawn(Self::heartbeat(self.config.pipe(), interval, self.config.heartbeat_exit_on_failure, self.state.clone(), heartbeat_failed_send, cancel.clone()));
+        }
 
         loop {
+            let throttled_until = throttled.as_ref().map(|(_, until)| *until).unwrap_or_else(tokio::time::Instant::now);
             tokio::select! {
                 // Handle incoming connections:
-                connection_result = server.connect() => {
-                    match connection_result {
-                        Ok(_) => {
-                            let counter = self.counter.clone();
-                            let mut connected_server = server;
-                            server = ServerOptions::new().create(Self::SERVICE_PIPE)?;
-                    
-                            let _client = tokio::spawn(async move {
-                                connected_server.write_u64(counter.fetch_add(1, Ordering::SeqCst)).await?;
-                                connected_server.disconnect()?;
-                                Ok::<(), std::io::Error>(())
-                            });
-                        },
-                        Err(err) => {
-                            log::error!("Named pipe connection error: {}", err);
+                Some(connection) = connected_recv.recv(), if throttled.is_none() => {
+                    let delay = rate_limiter.acquire();
+                    if delay.is_zero() {
+                        rate_limited = false;
+                        self.spawn_connection(connection, &cancel);
+                    } else {
+                        if !rate_limited {
+                            tracing::warn!(limit = self.config.max_connections_per_second, "Connection rate limit reached, delaying new connections");
                         }
+                        rate_limited = true;
+                        throttled = Some((connection, tokio::time::Instant::now() + delay));
+                    }
+                }
+
+                // Serve a delayed connection once its turn comes:
+                _ = tokio::time::sleep_until(throttled_until), if throttled.is_some() => {
+                    if let Some((connection, _)) = throttled.take() {
+                        self.spawn_connection(connection, &cancel);
+                    }
+                }
+
+                // Keep serving with the remaining listeners, the agent can't
+                // serve the pipe once none are left:
+                Some(generation) = stopped_recv.recv() => {
+                    // Listeners replaced after a resume no longer count.
+                    if generation == listeners.id {
+                        live_listeners -= 1;
+                        tracing::warn!(live_listeners, "A pipe listener stopped");
+                        if live_listeners == 0 {
+                            return Err(anyhow::anyhow!("all agent pipe listeners stopped"));
+                        }
+                    }
+                }
+
+                // Exit so the service manager can restart a wedged agent:
+                Some(()) = heartbeat_failed_recv.recv() => {
+                    return Err(anyhow::anyhow!("heartbeat check of the agent pipe failed"));
+                }
+
+                // Make sure the pipe survived a suspend:
+                Some(power_event) = self.power_recv.recv() => match power_event {
+                    PowerEvent::Suspend => tracing::info!("System is suspending"),
+                    PowerEvent::Resume => match Self::pipe_is_owned_on(&self.config.pipe()) {
+                        Ok(true) => tracing::info!("System resumed, agent pipe is still listening"),
+                        Ok(false) => {
+                            tracing::warn!("System resumed and the agent pipe is gone, recreating it");
+                            listeners.stop().await;
+                            listeners = self.spawn_listeners(listeners.id + 1, &connected_send, &stopped_send, &cancel)?;
+                            live_listeners = listener_count;
+                        },
+                        Err(err) => tracing::warn!(error = %err, "System resumed and the agent pipe could not be checked, assuming it is still listening"),
+                    },
+                },
+
+                // Handle configuration reload requests:
+                _ = self.reload_recv.recv() => {
+                    let max_connections_per_second = self.config.max_connections_per_second;
+                    if let Err(err) = self.reload_config() {
+                        tracing::error!(error = %err, "Failed to reload configuration");
+                    }
+                    if self.config.max_connections_per_second != max_connections_per_second {
+                        rate_limiter = RateLimiter::new(self.config.max_connections_per_second);
                     }
                 }
 
                 // Handle shutdown requests:
                 _ = self.shutdown_recv.recv() => {
                     self.shutdown_recv.close();
-                    let _ = server.disconnect();
+                    cancel.cancel();
+                    if let Some((connection, _)) = throttled.take() {
+                        Self::reject(connection).await;
+                    }
+                    Self::reject_pending(&mut connected_recv).await;
                     break;
                 }
             }
@@ -71,8 +1086,815 @@ impl Agent {
         Ok(())
     }
 
-    pub async fn query_status() -> anyhow::Result<u64> {
-        let mut client = ClientOptions::new().open(Self::SERVICE_PIPE)?;
-        Ok(client.read_u64().await?)
+    /// Start serving an accepted connection in its own task, registered as
+    /// a session.
+    fn spawn_connection(&mut self, connection: Connection, cancel: &CancellationToken) {
+        let connection_id = self.next_connection_id;
+        self.next_connection_id += 1;
+        self.state.metrics.connections_total.fetch_add(1, Ordering::Relaxed);
+        let client_pid = connection.client_pid();
+        let transport = connection.transport();
+        let span = tracing::info_span!("connection", id = connection_id, client_pid, ?transport);
+        span.in_scope(|| tracing::debug!("Accepted connection"));
+
+        let state = self.state.clone();
+        let handler = self.handler.clone();
+        let authenticator = self.authenticator.clone();
+        let request_read_timeout = self.config.request_read_timeout;
+        let max_message_size = self.config.max_message_size;
+        let cancel = cancel.clone();
+
+        self.state.sessions.spawn(connection_id, client_pid, async move {
+            let client = ClientContext::resolve(client_pid, transport);
+            let context = ConnectionContext { id: connection_id, client, request_read_timeout, max_message_size, cancel };
+            if !context.is_allowed_client(&state.allowed_client_images) {
+                tracing::info!("Rejected connection");
+                let _ = connection.disconnect();
+                return;
+            }
+            match Self::serve_connection(connection, &context, &state, handler.as_ref(), authenticator.as_ref()).await {
+                Ok(_) => tracing::debug!("Connection closed"),
+                Err(err) => tracing::warn!(error = %format_args!("{:#}", err), "Connection failed"),
+            }
+        }.instrument(span));
+    }
+
+    /// Serve requests from a connected client until it disconnects.
+    /// 
+    /// Clients that stall part way through a request, or that don't read
+    /// their response before the request deadline, are disconnected.
+    async fn serve_connection(mut connection: Connection, context: &ConnectionContext, state: &AgentState, handler: &dyn RequestHandler, authenticator: &dyn Authenticator) -> anyhow::Result<()> {
+        Self::serve_stream(&mut connection, context, state, handler, authenticator).await?;
+        connection.disconnect().context("failed to disconnect the client")
+    }
+
+    /// Serve requests read from `connection` until the client closes it,
+    /// until it has to be dropped, or until the agent shuts down.
+    /// 
+    /// A request that is being handled when the agent shuts down is still
+    /// answered. This is the transport independent part of
+    /// `serve_connection`, it works over any byte stream such as
+    /// `tokio::io::duplex`.
+    pub async fn serve_stream<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut S, context: &ConnectionContext, state: &AgentState, handler: &dyn RequestHandler, authenticator: &dyn Authenticator) -> anyhow::Result<()> {
+        loop {
+            let request = tokio::select! {
+                request = protocol::read_message_timeout::<_, AgentRequest>(connection, context.request_read_timeout, context.max_message_size) => request,
+                _ = context.cancelled() => {
+                    // Tell a client that is about to send a request why it
+                    // won't be answered, rather than closing on it.
+                    tracing::debug!("Agent is shutting down, closing connection");
+                    let _ = tokio::time::timeout(context.request_read_timeout, protocol::write_message(connection, &AgentResponse::ShuttingDown)).await;
+                    break;
+                },
+            };
+            let request = match request {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                    tracing::warn!("Client did not finish its request in time, disconnecting");
+                    state.metrics.request_errors_total.fetch_add(1, Ordering::Relaxed);
+                    let _ = tokio::time::timeout(context.request_read_timeout, protocol::write_message(connection, &AgentResponse::Timeout)).await;
+                    break;
+                },
+                Err(err) => return Err(anyhow::Error::new(err).context("failed to read a request")),
+            };
+
+            if let Err(err) = authenticator.authorize(&context.client, &request) {
+                tracing::warn!(error = %err, sid = ?context.client.sid, request = request.kind(), "Client is not authorized, disconnecting");
+                state.metrics.request_errors_total.fetch_add(1, Ordering::Relaxed);
+                let _ = tokio::time::timeout(context.request_read_timeout, protocol::write_message(connection, &AgentResponse::Error(err.to_string()))).await;
+                break;
+            }
+
+            tracing::debug!(?request, "Handling request");
+            state.metrics.requests_total.fetch_add(1, Ordering::Relaxed);
+            let deadline = request.deadline();
+            let served = tokio::time::timeout(deadline, async {
+                let response = handler.handle(request, context, state).await;
+                if let AgentResponse::Error(_) = &response {
+                    state.metrics.request_errors_total.fetch_add(1, Ordering::Relaxed);
+                }
+                protocol::write_message(connection, &response).await
+            }).await;
+            match served {
+                Ok(result) => result.context("failed to write the response")?,
+                Err(_) => {
+                    // The response may be partly written, so the stream can't
+                    // carry another message.
+                    tracing::warn!(?deadline, "Request missed its deadline, disconnecting");
+                    state.metrics.request_errors_total.fetch_add(1, Ordering::Relaxed);
+                    break;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a single request to the running agent and wait for its response.
+    /// 
+    /// `AgentResponse::Error` is returned as `ClientError::Agent`, so the
+    /// response is never an error.
+    pub async fn request(request: AgentRequest) -> Result<AgentResponse, ClientError> {
+        Self::request_on(&Self::service_pipe(), request).await
+    }
+
+    /// Send a single request to the agent serving `pipe`, like `request`.
+    pub async fn request_on(pipe: &str, request: AgentRequest) -> Result<AgentResponse, ClientError> {
+        let mut client = Self::connect_to(pipe)?;
+        Self::exchange(&mut client, &request).await
+    }
+
+    /// Open a connection to the running agent, which can carry any number
+    /// of `exchange`s.
+    pub fn connect() -> Result<NamedPipeClient, ClientError> {
+        Self::connect_to(&Self::service_pipe())
+    }
+
+    /// Open a connection to the agent serving `pipe`, like `connect`.
+    pub fn connect_to(pipe: &str) -> Result<NamedPipeClient, ClientError> {
+        match ClientOptions::new().open(pipe) {
+            Ok(client) => Ok(client),
+            // ERROR_FILE_NOT_FOUND and ERROR_ACCESS_DENIED.
+            Err(err) if err.raw_os_error() == Some(2) => Err(ClientError::AgentNotRunning),
+            Err(err) if err.raw_os_error() == Some(5) => Err(ClientError::AccessDenied),
+            Err(err) => Err(ClientError::Transport { context: "failed to connect to the agent", source: err }),
+        }
+    }
+
+    /// Open a connection to the agent over loopback TCP on `port`, for
+    /// agents with `tcp_port` configured.
+    pub async fn connect_tcp(port: u16) -> Result<TcpStream, ClientError> {
+        match TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await {
+            Ok(stream) => Ok(stream),
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => Err(ClientError::AgentNotRunning),
+            Err(err) => Err(ClientError::Transport { context: "failed to connect to the agent over TCP", source: err }),
+        }
+    }
+
+    /// Send a single request over an open connection to the agent and wait
+    /// for its response.
+    /// 
+    /// This is the transport independent part of `request`, it works over
+    /// any byte stream such as `tokio::io::duplex`.
+    pub async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut S, request: &AgentRequest) -> Result<AgentResponse, ClientError> {
+        protocol::write_message(connection, request).await.map_err(|err| Self::map_io_error(err, "failed to send the request to the agent"))?;
+        match protocol::read_message(connection, protocol::DEFAULT_MAX_MESSAGE_SIZE).await.map_err(|err| Self::map_io_error(err, "failed to read the agent response"))? {
+            Some(AgentResponse::Error(err)) => Err(ClientError::Agent(err)),
+            Some(AgentResponse::Timeout) => Err(ProtocolError::RequestTimedOut.into()),
+            Some(AgentResponse::ShuttingDown) => Err(ClientError::ShuttingDown),
+            Some(response) => Ok(response),
+            None => Err(ProtocolError::ConnectionClosed.into()),
+        }
+    }
+
+    /// Classify an error talking over an open connection.
+    /// 
+    /// The agent closing an accepted connection, for example while it is
+    /// shutting down, and messages that can't be decoded are protocol errors.
+    /// Other errors are transport errors with `context` describing what was
+    /// attempted.
+    fn map_io_error(err: std::io::Error, context: &'static str) -> ClientError {
+        // ERROR_BROKEN_PIPE, ERROR_NO_DATA, and ERROR_PIPE_NOT_CONNECTED.
+        let closed = matches!(err.kind(), std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::BrokenPipe)
+            || matches!(err.raw_os_error(), Some(109) | Some(232) | Some(233));
+        if closed {
+            ProtocolError::ConnectionClosed.into()
+        } else if matches!(err.kind(), std::io::ErrorKind::InvalidData | std::io::ErrorKind::InvalidInput) {
+            ProtocolError::InvalidMessage(err).into()
+        } else {
+            ClientError::Transport { context, source: err }
+        }
+    }
+
+    /// Query the agent counter, without incrementing it.
+    /// 
+    /// Gives up after `STATUS_TIMEOUT` with `ClientError::NotResponding`, so
+    /// an agent that accepted the connection while stopping doesn't look
+    /// hung.
+    pub async fn query_status() -> Result<u64, ClientError> {
+        Self::query_status_on(&Self::service_pipe()).await
+    }
+
+    /// Query the counter of the agent serving `pipe`, like `query_status`.
+    pub async fn query_status_on(pipe: &str) -> Result<u64, ClientError> {
+        let response = tokio::time::timeout(Self::STATUS_TIMEOUT, Self::request_on(pipe, AgentRequest::GetCounter)).await
+            .map_err(|_| ClientError::NotResponding(Self::STATUS_TIMEOUT))??;
+        match response {
+            AgentResponse::Counter(counter) => Ok(counter),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Query the agent counter over loopback TCP on `port`, like
+    /// `query_status`.
+    pub async fn query_status_tcp(port: u16) -> Result<u64, ClientError> {
+        let response = tokio::time::timeout(Self::STATUS_TIMEOUT, async {
+            let mut stream = Self::connect_tcp(port).await?;
+            Self::exchange(&mut stream, &AgentRequest::GetCounter).await
+        }).await.map_err(|_| ClientError::NotResponding(Self::STATUS_TIMEOUT))??;
+        match response {
+            AgentResponse::Counter(counter) => Ok(counter),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Query the agent counter, returning `None` if no agent is running.
+    /// 
+    /// Unlike `query_status`, an agent that is not running is not an error,
+    /// only failures talking to a running agent are.
+    pub async fn try_query_status() -> Result<Option<u64>, ClientError> {
+        Self::try_query_status_on(&Self::service_pipe()).await
+    }
+
+    /// Query the counter of the agent serving `pipe`, like
+    /// `try_query_status`.
+    pub async fn try_query_status_on(pipe: &str) -> Result<Option<u64>, ClientError> {
+        match Self::query_status_on(pipe).await {
+            Ok(counter) => Ok(Some(counter)),
+            Err(ClientError::AgentNotRunning) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Wait until the agent answers over its pipe, polling `try_query_status`.
+    /// 
+    /// The service manager reports the agent as running before its pipe is
+    /// created, so this is the check that it can actually serve clients.
+    /// Returns the counter from the first successful query, or
+    /// `ClientError::NotReady` if the agent is not serving within `timeout`.
+    /// Each query is cut short at the timeout too, so a query that hangs
+    /// is reported as `NotReady` rather than overrunning it. Failures other
+    /// than a missing or busy pipe are returned immediately.
+    pub async fn wait_until_ready(timeout: Duration) -> Result<u64, ClientError> {
+        Self::wait_until_ready_on(&Self::service_pipe(), timeout).await
+    }
+
+    /// Wait until the agent answers over `pipe`, like `wait_until_ready`.
+    pub async fn wait_until_ready_on(pipe: &str, timeout: Duration) -> Result<u64, ClientError> {
+        let started = tokio::time::Instant::now();
+        loop {
+            let remaining = timeout.saturating_sub(started.elapsed());
+            let result = match tokio::time::timeout(remaining, Self::try_query_status_on(pipe)).await {
+                Ok(result) => result,
+                Err(_) => return Err(ClientError::NotReady(started.elapsed())),
+            };
+            match result {
+                Ok(Some(counter)) => return Ok(counter),
+                Ok(None) => {},
+                // ERROR_PIPE_BUSY, every instance is in use.
+                Err(ClientError::Transport { source, .. }) if source.raw_os_error() == Some(231) => {},
+                Err(err) => return Err(err),
+            }
+            if started.elapsed() >= timeout {
+                return Err(ClientError::NotReady(started.elapsed()));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Query the running agent's metrics in the Prometheus text exposition format.
+    pub async fn query_metrics() -> Result<String, ClientError> {
+        match Self::request(AgentRequest::Metrics).await? {
+            AgentResponse::Metrics(metrics) => Ok(metrics),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Query the running agent's build information.
+    pub async fn query_version() -> Result<VersionInfo, ClientError> {
+        Self::query_version_on(&Self::service_pipe()).await
+    }
+
+    /// Query the build information of the agent serving `pipe`, like
+    /// `query_version`.
+    pub async fn query_version_on(pipe: &str) -> Result<VersionInfo, ClientError> {
+        match Self::request_on(pipe, AgentRequest::Version).await? {
+            AgentResponse::VersionInfo(version) => Ok(version),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// List the sessions currently served by the running agent.
+    pub async fn query_sessions() -> Result<Vec<SessionInfo>, ClientError> {
+        match Self::request(AgentRequest::ListSessions).await? {
+            AgentResponse::Sessions(sessions) => Ok(sessions),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Kill a session served by the running agent.
+    pub async fn kill_session(id: u64) -> Result<(), ClientError> {
+        match Self::request(AgentRequest::KillSession { id }).await? {
+            AgentResponse::SessionKilled(_) => Ok(()),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Query the configuration the running agent uses.
+    pub async fn query_config() -> Result<AgentConfig, ClientError> {
+        match Self::request_if_supported(AgentRequest::GetConfig).await? {
+            AgentResponse::Config(config) => Ok(config),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Query a snapshot of the running agent's state, requires an elevated
+    /// client.
+    pub async fn dump_state() -> Result<StateDump, ClientError> {
+        match Self::request_if_supported(AgentRequest::DumpState).await? {
+            AgentResponse::StateDump(dump) => Ok(dump),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Take the next value of the running agent's counter, incrementing it.
+    pub async fn next_counter() -> Result<u64, ClientError> {
+        match Self::request_if_supported(AgentRequest::NextCounter).await? {
+            AgentResponse::Counter(counter) => Ok(counter),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Reserve `count` consecutive values of the running agent's counter,
+    /// returning the first one.
+    pub async fn reserve_counter(count: u64) -> Result<u64, ClientError> {
+        match Self::request_if_supported(AgentRequest::ReserveCounter { count }).await? {
+            AgentResponse::CounterRange { start, .. } => Ok(start),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Query the request kinds and features the running agent supports.
+    pub async fn query_capabilities() -> Result<Capabilities, ClientError> {
+        match Self::request(AgentRequest::Capabilities).await? {
+            AgentResponse::Capabilities(capabilities) => Ok(capabilities),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+
+    /// Send `request` to the running agent only if it supports it, returning
+    /// `ClientError::Unsupported` otherwise.
+    /// 
+    /// Agents that predate capabilities close the connection on the
+    /// capabilities query, so they are treated as supporting nothing newer.
+    pub async fn request_if_supported(request: AgentRequest) -> Result<AgentResponse, ClientError> {
+        match Self::query_capabilities().await {
+            Ok(capabilities) if capabilities.supports(request.kind()) => Self::request(request).await,
+            Ok(_) | Err(ClientError::Protocol(ProtocolError::ConnectionClosed)) => Err(ClientError::Unsupported(request.kind())),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reset the running agent's counter to zero, returning its previous
+    /// value. The agent only accepts this from elevated clients.
+    pub async fn reset_counter() -> Result<u64, ClientError> {
+        match Self::request(AgentRequest::ResetCounter).await? {
+            AgentResponse::CounterReset { previous } => Ok(previous),
+            response => Err(ProtocolError::unexpected(&response).into()),
+        }
+    }
+}
+
+impl Default for Agent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn test_state() -> AgentState {
+        AgentState {
+            counter: AtomicU64::new(0),
+            metrics: AgentMetrics::new(),
+            sessions: Arc::new(SessionRegistry::default()),
+            allowed_client_images: Vec::new(),
+            config: Mutex::new(AgentConfig::default()),
+        }
+    }
+
+    fn test_context(client: ClientContext) -> ConnectionContext {
+        ConnectionContext {
+            id: 1,
+            client,
+            request_read_timeout: Duration::from_secs(5),
+            max_message_size: protocol::DEFAULT_MAX_MESSAGE_SIZE,
+            cancel: CancellationToken::default(),
+        }
+    }
+
+    /// Refuses every request.
+    struct DenyAll;
+
+    impl Authenticator for DenyAll {
+        fn authorize(&self, _client: &ClientContext, request: &AgentRequest) -> Result<(), AuthError> {
+            Err(AuthError::Denied(format!("test refuses {}", request.kind())))
+        }
+    }
+
+    /// Allows every request.
+    struct AllowAll;
+
+    impl Authenticator for AllowAll {
+        fn authorize(&self, _client: &ClientContext, _request: &AgentRequest) -> Result<(), AuthError> {
+            Ok(())
+        }
+    }
+
+    /// Answers `GetCounter` with 42, and every other request with a
+    /// response that does not match it.
+    struct StubHandler;
+
+    impl RequestHandler for StubHandler {
+        fn handle<'a>(&'a self, request: AgentRequest, _context: &'a ConnectionContext, _state: &'a AgentState) -> HandlerFuture<'a> {
+            Box::pin(async move {
+                match request {
+                    AgentRequest::GetCounter => AgentResponse::Counter(42),
+                    _ => AgentResponse::SessionKilled(0),
+                }
+            })
+        }
+    }
+
+    /// Answers every request with the number of requests it has handled.
+    #[derive(Default)]
+    struct CountingHandler(AtomicU64);
+
+    impl RequestHandler for CountingHandler {
+        fn handle<'a>(&'a self, _request: AgentRequest, _context: &'a ConnectionContext, _state: &'a AgentState) -> HandlerFuture<'a> {
+            Box::pin(async move { AgentResponse::Counter(self.0.fetch_add(1, Ordering::SeqCst) + 1) })
+        }
+    }
+
+    #[tokio::test]
+    async fn agent_dispatches_requests_to_its_handler() {
+        let agent = Agent::with_handler(AgentConfig::default(), Arc::new(CountingHandler::default()));
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let context = test_context(ClientContext::default());
+
+        let (served, ()) = tokio::join!(
+            Agent::serve_stream(&mut server, &context, &agent.state, agent.handler.as_ref(), &AllowAll),
+            async move {
+                for expected in 1..=3 {
+                    let response = Agent::exchange(&mut client, &AgentRequest::NextCounter).await.unwrap();
+                    assert!(matches!(response, AgentResponse::Counter(counter) if counter == expected), "{:?}", response);
+                }
+            },
+        );
+        served.unwrap();
+        // The built-in handler was bypassed.
+        assert_eq!(agent.state.counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn serve_stream_answers_each_request() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let state = test_state();
+        let context = test_context(ClientContext::default());
+
+        let (served, ()) = tokio::join!(
+            Agent::serve_stream(&mut server, &context, &state, &StubHandler, &AllowAll),
+            async move {
+                for _ in 0..2 {
+                    let response = Agent::exchange(&mut client, &AgentRequest::GetCounter).await.unwrap();
+                    assert!(matches!(response, AgentResponse::Counter(42)), "{:?}", response);
+                }
+            },
+        );
+        served.unwrap();
+        assert_eq!(state.metrics.requests_total.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn serve_stream_keeps_serving_after_an_unexpected_response() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let state = test_state();
+        let context = test_context(ClientContext::default());
+
+        let (served, ()) = tokio::join!(
+            Agent::serve_stream(&mut server, &context, &state, &StubHandler, &AllowAll),
+            async move {
+                let err = match Agent::exchange(&mut client, &AgentRequest::Version).await.unwrap() {
+                    AgentResponse::VersionInfo(version) => panic!("expected a mismatched response, got {:?}", version),
+                    response => ProtocolError::unexpected(&response),
+                };
+                assert!(matches!(&err, ProtocolError::UnexpectedResponse(response) if response.contains("SessionKilled")), "{:?}", err);
+
+                let response = Agent::exchange(&mut client, &AgentRequest::GetCounter).await.unwrap();
+                assert!(matches!(response, AgentResponse::Counter(42)), "{:?}", response);
+            },
+        );
+        served.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serve_stream_times_out_a_stalled_request() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let state = test_state();
+        let context = test_context(ClientContext::default());
+
+        // Send part of a frame and stall.
+        client.write_all(&100u32.to_be_bytes()).await.unwrap();
+        Agent::serve_stream(&mut server, &context, &state, &StubHandler, &AllowAll).await.unwrap();
+
+        let response = protocol::read_message::<_, AgentResponse>(&mut client, protocol::DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+        assert!(matches!(response, Some(AgentResponse::Timeout)), "{:?}", response);
+        assert_eq!(state.metrics.request_errors_total.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn serve_stream_fails_when_the_client_hangs_up_mid_frame() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let state = test_state();
+        let context = test_context(ClientContext::default());
+
+        // Announce a 100 byte body, send part of it, and hang up.
+        client.write_all(&100u32.to_be_bytes()).await.unwrap();
+        client.write_all(br#"{"Kill"#).await.unwrap();
+        drop(client);
+
+        let err = Agent::serve_stream(&mut server, &context, &state, &StubHandler, &AllowAll).await.unwrap_err();
+        let io_err = err.downcast_ref::<std::io::Error>().expect("an I/O error");
+        assert_eq!(io_err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(state.metrics.requests_total.load(Ordering::Relaxed), 0);
+    }
+
+    /// Pipe name unique to this test process and `name`, so tests never
+    /// talk to an installed agent or to each other.
+    fn test_pipe(name: &str) -> String {
+        format!(r"\\.\pipe\porcelet-test-{}-{}", std::process::id(), name)
+    }
+
+    /// Send `request` to the agent serving `pipe`, retrying while every
+    /// pipe instance is busy.
+    async fn request_when_free(pipe: &str, request: AgentRequest) -> Result<AgentResponse, ClientError> {
+        loop {
+            match Agent::request_on(pipe, request.clone()).await {
+                // ERROR_PIPE_BUSY.
+                Err(ClientError::Transport { source, .. }) if source.raw_os_error() == Some(231) => tokio::time::sleep(Duration::from_millis(5)).await,
+                result => return result,
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn agent_serves_many_concurrent_clients() {
+        const CLIENTS: u64 = 64;
+        const REQUESTS_PER_CLIENT: u64 = 16;
+
+        // A pipe of its own, so the test never talks to an installed agent.
+        let pipe = test_pipe("many-clients");
+        let config = AgentConfig { listener_count: 4, max_connections_per_second: 0, pipe_name: Some(pipe.clone()), ..AgentConfig::default() };
+        let mut agent = Agent::with_config(config);
+        let shutdown = agent.shutdown_handle();
+        let running = tokio::spawn(async move { agent.run().await });
+        Agent::wait_until_ready_on(&pipe, Duration::from_secs(10)).await.unwrap();
+
+        let clients: Vec<_> = (0..CLIENTS).map(|_| tokio::spawn({
+            let pipe = pipe.clone();
+            async move {
+                let mut counters = Vec::new();
+                for _ in 0..REQUESTS_PER_CLIENT {
+                    match request_when_free(&pipe, AgentRequest::NextCounter).await.unwrap() {
+                        AgentResponse::Counter(counter) => counters.push(counter),
+                        response => panic!("unexpected response {:?}", response),
+                    }
+                }
+                counters
+            }
+        })).collect();
+        let mut counters = Vec::new();
+        for client in clients {
+            counters.extend(client.await.unwrap());
+        }
+
+        // Every request was served exactly once.
+        counters.sort_unstable();
+        assert_eq!(counters, (0..CLIENTS * REQUESTS_PER_CLIENT).collect::<Vec<_>>());
+
+        shutdown.request_shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn wait_until_ready_waits_for_an_agent_that_starts_late() {
+        let pipe = test_pipe("starts-late");
+        let config = AgentConfig { pipe_name: Some(pipe.clone()), ..AgentConfig::default() };
+        let mut agent = Agent::with_config(config);
+        let shutdown = agent.shutdown_handle();
+        let started = tokio::time::Instant::now();
+        let running = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            agent.run().await
+        });
+
+        assert_eq!(Agent::wait_until_ready_on(&pipe, Duration::from_secs(10)).await.unwrap(), 0);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        shutdown.request_shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_until_ready_reports_not_ready_when_nothing_serves() {
+        let pipe = test_pipe("never-serves");
+        let result = Agent::wait_until_ready_on(&pipe, Duration::from_secs(1)).await;
+        match result {
+            Err(ClientError::NotReady(elapsed)) => assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn agent_serves_the_same_state_over_tcp_and_the_pipe() {
+        // Let the system pick a free loopback port for the agent.
+        let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let pipe = test_pipe("tcp");
+        let config = AgentConfig { tcp_port: Some(port), pipe_name: Some(pipe.clone()), ..AgentConfig::default() };
+        let mut agent = Agent::with_config(config);
+        let shutdown = agent.shutdown_handle();
+        let running = tokio::spawn(async move { agent.run().await });
+        Agent::wait_until_ready_on(&pipe, Duration::from_secs(10)).await.unwrap();
+
+        assert_eq!(Agent::query_status_tcp(port).await.unwrap(), 0);
+        let mut stream = Agent::connect_tcp(port).await.unwrap();
+        assert!(matches!(Agent::exchange(&mut stream, &AgentRequest::NextCounter).await, Ok(AgentResponse::Counter(0))));
+        assert_eq!(Agent::query_status_on(&pipe).await.unwrap(), 1);
+        assert_eq!(Agent::query_status_tcp(port).await.unwrap(), 1);
+
+        let over_tcp = match Agent::exchange(&mut Agent::connect_tcp(port).await.unwrap(), &AgentRequest::Version).await.unwrap() {
+            AgentResponse::VersionInfo(version) => version,
+            response => panic!("unexpected response {:?}", response),
+        };
+        assert_eq!(over_tcp.to_string(), Agent::query_version_on(&pipe).await.unwrap().to_string());
+
+        match Agent::request_on(&pipe, AgentRequest::Capabilities).await.unwrap() {
+            AgentResponse::Capabilities(capabilities) => assert_eq!(capabilities.endpoints, [format!("pipe:{}", pipe), format!("tcp:127.0.0.1:{}", port)]),
+            response => panic!("unexpected response {:?}", response),
+        }
+
+        shutdown.request_shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn exchange_reports_an_agent_that_closes_before_responding() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let agent = async move {
+            // Take the request, then close without answering, like an agent
+            // that is shutting down.
+            protocol::read_message::<_, AgentRequest>(&mut server, protocol::DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+        };
+        let (response, ()) = tokio::join!(Agent::exchange(&mut client, &AgentRequest::GetCounter), agent);
+        assert!(matches!(response, Err(ClientError::Protocol(ProtocolError::ConnectionClosed))), "{:?}", response);
+        assert_eq!(response.unwrap_err().to_string(), "agent closed the connection before responding");
+    }
+
+    #[tokio::test]
+    async fn exchange_reports_an_agent_that_closes_right_after_accepting() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        drop(server);
+        let response = Agent::exchange(&mut client, &AgentRequest::GetCounter).await;
+        assert!(matches!(response, Err(ClientError::Protocol(ProtocolError::ConnectionClosed))), "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn child_token_is_cancelled_with_its_parent_but_not_the_reverse() {
+        let parent = CancellationToken::default();
+        let child = parent.child();
+        child.cancel();
+        tokio::time::timeout(Duration::from_secs(1), child.cancelled()).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(50), parent.cancelled()).await.is_err());
+
+        let child = parent.child();
+        parent.cancel();
+        tokio::time::timeout(Duration::from_secs(1), child.cancelled()).await.unwrap();
+    }
+
+    #[test]
+    fn default_authenticator_allows_read_only_requests_for_any_user() {
+        let authenticator = DefaultAuthenticator { agent_sid: Some("S-1-5-21-1-2-3-1000".to_string()), allow_unidentified_tcp: false };
+        let user = ClientContext { pid: Some(1234), sid: Some("S-1-5-21-1-2-3-1001".to_string()), ..ClientContext::default() };
+
+        for request in [AgentRequest::GetCounter, AgentRequest::Version, AgentRequest::Capabilities, AgentRequest::Metrics] {
+            assert!(authenticator.authorize(&user, &request).is_ok(), "{} was refused", request.kind());
+        }
+        for request in [AgentRequest::ResetCounter, AgentRequest::KillSession { id: 2 }, AgentRequest::DumpState] {
+            assert!(matches!(authenticator.authorize(&user, &request), Err(AuthError::Denied(_))), "{} was allowed", request.kind());
+        }
+    }
+
+    #[test]
+    fn default_authenticator_allows_privileged_requests_for_admins_and_the_agent_user() {
+        let authenticator = DefaultAuthenticator { agent_sid: Some("S-1-5-21-1-2-3-1000".to_string()), allow_unidentified_tcp: false };
+        let admin = ClientContext { pid: Some(1234), sid: Some("S-1-5-21-1-2-3-1001".to_string()), elevated: true, ..ClientContext::default() };
+        let system = ClientContext { pid: Some(1234), sid: Some(DefaultAuthenticator::LOCAL_SYSTEM_SID.to_string()), ..ClientContext::default() };
+        let agent_user = ClientContext { pid: Some(1234), sid: Some("S-1-5-21-1-2-3-1000".to_string()), ..ClientContext::default() };
+
+        for client in [admin, system, agent_user] {
+            assert!(authenticator.authorize(&client, &AgentRequest::DumpState).is_ok(), "{:?} was refused", client);
+        }
+        assert!(matches!(authenticator.authorize(&ClientContext::default(), &AgentRequest::GetCounter), Err(AuthError::UnknownClient)));
+    }
+
+    fn tcp_allowing_authenticator() -> DefaultAuthenticator {
+        DefaultAuthenticator { agent_sid: None, allow_unidentified_tcp: false }.allow_unidentified_tcp(true)
+    }
+
+    #[test]
+    fn default_authenticator_refuses_unidentified_tcp_clients_unless_allowed() {
+        let tcp = ClientContext { transport: Transport::Tcp, ..ClientContext::default() };
+        let refusing = DefaultAuthenticator { agent_sid: None, allow_unidentified_tcp: false };
+        let allowing = tcp_allowing_authenticator();
+
+        assert!(matches!(refusing.authorize(&tcp, &AgentRequest::GetCounter), Err(AuthError::UnknownClient)));
+        assert!(allowing.authorize(&tcp, &AgentRequest::GetCounter).is_ok());
+        assert!(matches!(allowing.authorize(&tcp, &AgentRequest::NextCounter), Err(AuthError::UnknownClient)));
+        assert!(matches!(allowing.authorize(&ClientContext::default(), &AgentRequest::GetCounter), Err(AuthError::UnknownClient)));
+    }
+
+    #[tokio::test]
+    async fn serve_stream_serves_an_unidentified_tcp_client_read_only_requests() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+        let (mut client, (mut server, _)) = (client.unwrap(), accepted.unwrap());
+        let state = test_state();
+        let context = test_context(ClientContext { transport: Transport::Tcp, ..ClientContext::default() });
+        let authenticator = tcp_allowing_authenticator();
+
+        let (served, ()) = tokio::join!(
+            Agent::serve_stream(&mut server, &context, &state, &DefaultRequestHandler, &authenticator),
+            async move {
+                let response = Agent::exchange(&mut client, &AgentRequest::GetCounter).await.unwrap();
+                assert!(matches!(response, AgentResponse::Counter(0)), "{:?}", response);
+
+                let err = Agent::exchange(&mut client, &AgentRequest::ResetCounter).await.unwrap_err();
+                assert!(matches!(&err, ClientError::Agent(err) if err.contains("could not be determined")), "{:?}", err);
+            },
+        );
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_request_is_answered_with_an_error() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let state = test_state();
+        let context = test_context(ClientContext::default());
+
+        let (served, response) = tokio::join!(
+            Agent::serve_stream(&mut server, &context, &state, &DefaultRequestHandler, &DenyAll),
+            Agent::exchange(&mut client, &AgentRequest::GetCounter),
+        );
+        served.unwrap();
+        assert!(matches!(response, Err(ClientError::Agent(err)) if err.contains("test refuses GetCounter")));
+        assert_eq!(state.metrics.requests_total.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn concurrent_reservations_do_not_overlap() {
+        const THREADS: u64 = 8;
+        const RESERVATIONS: u64 = 1000;
+        let state = Arc::new(test_state());
+
+        let threads: Vec<_> = (0..THREADS).map(|thread| {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                let context = test_context(ClientContext::default());
+                (0..RESERVATIONS).map(|i| {
+                    let count = 1 + (thread + i) % 5;
+                    match DefaultRequestHandler::handle_request(AgentRequest::ReserveCounter { count }, &context, &state) {
+                        AgentResponse::CounterRange { start, count } => (start, count),
+                        response => panic!("unexpected response {:?}", response),
+                    }
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+
+        let mut ranges: Vec<(u64, u64)> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+        ranges.sort_unstable();
+        // Sorted by start, each range must begin exactly where the previous
+        // one ended, with no overlap and no skipped values.
+        let mut next = 0;
+        for (start, count) in ranges {
+            assert_eq!(start, next);
+            next = start + count;
+        }
+        assert_eq!(state.counter.load(Ordering::SeqCst), next);
+    }
+
+    #[test]
+    fn allowlist_matches_the_client_image_ignoring_case() {
+        let allowed = [PathBuf::from(r"C:\Program Files\Porcelet\porcelet.exe")];
+        let context = |image: Option<&str>| test_context(ClientContext { image_path: image.map(PathBuf::from), ..ClientContext::default() });
+
+        assert!(context(Some(r"c:\program files\porcelet\PORCELET.EXE")).is_allowed_client(&allowed));
+        assert!(!context(Some(r"C:\Users\Public\porcelet.exe")).is_allowed_client(&allowed));
+        assert!(!context(None).is_allowed_client(&allowed));
+        // An empty allowlist allows every client.
+        assert!(context(None).is_allowed_client(&[]));
     }
 }
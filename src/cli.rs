@@ -1,16 +1,64 @@
-use std::ffi::OsString;
+use std::{ffi::{OsStr, OsString}, fmt::Display, future::Future, path::PathBuf, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
+use anyhow::Context;
 use clap::Parser;
+use thiserror::Error;
 use tokio::runtime::Runtime;
-use windows_service::service_dispatcher;
+use windows_service::{service_dispatcher, service::{ServiceStartType, ServiceSidType, ServiceExitCode, ServiceControlAccept}};
 
-use crate::{service::{SystemService, ServiceStatus, ServiceDescription}, agent::Agent, ffi_service_main};
+use crate::{service::{SystemService, ServiceStatus, ServiceDescription, ServiceError, ExportedService, Installed}, agent::{Agent, ClientError}, config::AgentConfig, doctor, eventlog, exit_code::ExitCode, repl, protocol::{AgentRequest, AgentResponse, ProtocolError, VersionInfo}, sddl, timestamp::format_utc, win32, ffi_service_main};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 pub struct CliArgs {
     #[clap(subcommand)]
     subcommand: CliSubcommand,
+
+    /// Seconds to wait for the agent to respond before giving up.
+    #[clap(long, global = true, default_value = "5", value_name = "SECONDS")]
+    timeout: u64,
+
+    /// Format of agent lifecycle command results.
+    #[clap(long, global = true, arg_enum, default_value = "text")]
+    format: OutputFormat,
+
+    /// Only print command results and errors, not progress or details.
+    #[clap(long, short, global = true)]
+    quiet: bool,
+
+    /// Log filter, such as 'debug' or 'porcelet=trace', overriding RUST_LOG.
+    #[clap(long, global = true, value_name = "FILTER")]
+    log_level: Option<String>,
+
+    /// Agent instance to install, run, or query, for running several agents
+    /// on one machine. Each instance has its own service, pipe, and data
+    /// directory, the default instance has none.
+    #[clap(long, global = true, value_name = "NAME", value_parser = parse_instance)]
+    instance: Option<String>,
+}
+
+/// Parse an `--instance` name.
+fn parse_instance(instance: &str) -> Result<String, String> {
+    Agent::validate_instance(instance)?;
+    Ok(instance.to_string())
+}
+
+impl CliArgs {
+    /// Log filter given on the command line, if any.
+    pub fn log_level(&self) -> Option<&str> {
+        self.log_level.as_deref()
+    }
+
+    /// Agent instance given on the command line, if any.
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+
+    /// Returns true if the command runs the agent itself, as a service or in
+    /// the foreground, rather than managing or querying it.
+    pub fn runs_agent(&self) -> bool {
+        matches!(&self.subcommand, CliSubcommand::Agent { agent_subcommand: AgentSubcommand::Foreground | AgentSubcommand::Run | AgentSubcommand::RunWindowsService, .. })
+    }
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -18,26 +66,214 @@ pub struct CliArgs {
 pub enum CliSubcommand {
     /// Control the procelet agent.
     Agent {
+        /// Manage the agent service on this remote machine instead of the
+        /// local one. Requires administrator rights on the remote machine,
+        /// and install expects porcelet at the same path there.
+        #[clap(long, value_name = "NAME")]
+        machine: Option<String>,
+
+        /// Seconds to wait for the agent service to start or stop, for
+        /// subcommands that wait.
+        #[clap(long, default_value = "30", value_name = "SECONDS")]
+        wait_timeout: u64,
+
+        /// Milliseconds between service status checks while waiting.
+        #[clap(long, default_value = "250", value_name = "MILLISECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+        poll_interval: u64,
+
         #[clap(subcommand)]
         agent_subcommand: AgentSubcommand,
     },
     /// Show the status of the porcelet agent.
-    Status,
+    Status {
+        /// Wait, up to the timeout, until the agent answers over its pipe
+        /// first. Use after starting the agent, which is reported running
+        /// before it serves clients.
+        #[clap(long)]
+        wait_ready: bool,
+
+        /// Only query the agent over loopback TCP on this port, as a client
+        /// that can't open the pipe would, instead of reporting the service.
+        #[clap(long, value_name = "PORT", conflicts_with = "wait_ready")]
+        tcp_port: Option<u16>,
+    },
+    /// Print the running agent's metrics in the Prometheus text format.
+    Metrics,
+    /// List the sessions served by the running agent.
+    Sessions {
+        /// Kill the session with this ID instead of listing sessions.
+        #[clap(long, value_name = "ID")]
+        kill: Option<u64>,
+    },
+    /// Reset the running agent's counter to zero. Requires an elevated
+    /// prompt.
+    ResetCounter,
+    /// Reserve a block of consecutive counter values from the running agent
+    /// and print the range.
+    ReserveCounter {
+        /// Number of values to reserve.
+        #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+        count: u64,
+    },
+    /// Inspect the agent configuration.
+    Config {
+        #[clap(subcommand)]
+        config_subcommand: ConfigSubcommand,
+    },
+    /// Diagnose common problems with the agent installation.
+    Doctor,
+    /// Print the agent's recent entries in the Windows Application event
+    /// log. Works without a running agent.
+    Eventlog {
+        /// Number of entries to print.
+        #[clap(short = 'n', long, default_value = "20")]
+        count: usize,
+    },
+    /// Print a JSON snapshot of the running agent's internals for
+    /// troubleshooting: counter, uptime, request and error counts,
+    /// sessions, and configuration. Requires an elevated prompt.
+    DumpState,
+    /// Show who can access the agent pipe, as SDDL and as a list of
+    /// allowed and denied principals.
+    PipeAcl,
+    /// Check that the running agent answers over its pipe, reporting the
+    /// latency of each step, the agent version, and the counter.
+    ConnectTest,
+    /// Type requests to the running agent at an interactive prompt, such as
+    /// 'counter', 'sessions', or 'kill <id>'. Type 'help' at the prompt for
+    /// the full list, and 'exit' or end of input to leave.
+    Repl,
+    /// Measure request round trips to the running agent, for tuning buffer
+    /// sizes and listener counts. Reports requests per second and latency
+    /// percentiles. Each request opens its own connection.
+    #[clap(hide = true)]
+    Bench {
+        /// Seconds to send requests for.
+        #[clap(long, default_value = "10", value_name = "SECONDS")]
+        duration: u64,
+        /// Stop after this many requests in total, if sooner than the
+        /// duration.
+        #[clap(long)]
+        count: Option<u64>,
+        /// Number of clients sending requests at the same time.
+        #[clap(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+        concurrency: u64,
+        /// Send NextCounter requests, which increment the counter, instead of
+        /// Version requests.
+        #[clap(long)]
+        counter: bool,
+    },
+    /// Show porcelet build information.
+    Version {
+        /// Query the running agent instead of reporting this binary.
+        #[clap(long)]
+        agent: bool,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+#[clap(author, version, about)]
+pub enum ConfigSubcommand {
+    /// Print the agent configuration as JSON. By default this is the
+    /// configuration an agent started now would load.
+    Show {
+        /// Query the configuration the running agent uses instead.
+        #[clap(long)]
+        running: bool,
+    },
 }
 
 #[derive(clap::Subcommand, Debug)]
 #[clap(author, version, about)]
 pub enum AgentSubcommand {
     /// Install the porcelet agent service on the machine.
-    Install,
+    Install {
+        /// Service that must start before the agent, may be repeated.
+        #[clap(long = "depends-on", value_name = "SERVICE")]
+        depends_on: Vec<String>,
+        /// When the service manager should start the agent.
+        #[clap(long, arg_enum, default_value = "auto")]
+        start_type: StartType,
+        /// How the service SID is added to the agent's token. Restricted
+        /// limits the agent to writing objects that grant access to its
+        /// service SID, the agent only needs its pipe.
+        #[clap(long, arg_enum, default_value = "restricted")]
+        sid_type: SidType,
+        /// Start the agent right after installing it and wait until it is
+        /// running. Combine with '--start-type manual' to run the agent now
+        /// without starting it on boot.
+        #[clap(long)]
+        start_now: bool,
+        /// Run the agent as this group managed service account (gMSA),
+        /// written 'DOMAIN\name$'. No password is needed, Windows retrieves
+        /// it from Active Directory.
+        #[clap(long, value_name = "ACCOUNT")]
+        gmsa: Option<String>,
+        /// Extra argument passed to the agent when the service starts, may
+        /// be repeated. For example '--arg --log-level=debug'.
+        #[clap(long = "arg", value_name = "ARG", allow_hyphen_values = true)]
+        extra_args: Vec<String>,
+    },
     /// Uninstall the porcelet agent service on the machine.
-    Uninstall,
+    Uninstall {
+        /// Seconds to wait for a service that is stopping to stop before
+        /// removing it, instead of failing right away.
+        #[clap(long, value_name = "SECONDS")]
+        wait: Option<u64>,
+    },
     /// Start the porcelet agent service.
     Start,
     /// Stop the porcelet agent service.
     Stop,
+    /// Forcibly remove a porcelet agent service that is stuck stopping or
+    /// will not uninstall. Stops the service, waits for it to stop, then
+    /// deletes it.
+    #[clap(hide = true)]
+    Purge {
+        /// Kill the agent process if it does not stop in time.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Change the display name of the installed porcelet agent service.
+    SetDisplayName {
+        /// New display name shown in the service manager. Taken as is, so
+        /// names that aren't valid Unicode are kept intact.
+        #[clap(value_parser)]
+        name: OsString,
+    },
+    /// Export the porcelet agent service configuration as JSON, to replicate
+    /// it on another machine with 'import-config'. Passwords are never
+    /// exported.
+    ExportConfig {
+        /// Write the JSON to this file instead of standard output.
+        #[clap(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Install or update the porcelet agent service from JSON written by
+    /// 'export-config'.
+    ImportConfig {
+        /// JSON file written by 'export-config'.
+        file: PathBuf,
+        /// Password of the service account, if it needs one.
+        #[clap(long, value_parser)]
+        password: Option<OsString>,
+    },
+    /// Compare the installed porcelet agent service configuration against
+    /// JSON written by 'export-config', failing if any field differs.
+    CheckConfig {
+        /// JSON file with the desired configuration.
+        file: PathBuf,
+    },
+    /// Print the raw service manager configuration of the porcelet agent
+    /// service, for debugging.
+    #[clap(hide = true)]
+    DumpConfig,
+    /// Run the porcelet agent in the foreground without installing it as a
+    /// service, for containers or ad-hoc use. Serves the agent pipe until
+    /// Ctrl-C or Ctrl-Break. Set RUST_LOG to change the log level.
+    Foreground,
     /// Run the porcelet agent service as a process. This should
-    /// not be used directly except for testing.
+    /// not be used directly except for testing, use 'foreground' instead.
     #[clap(hide = true)]
     Run,
     /// Run the porcelet agent service as Windows service. Sets up the
@@ -48,75 +284,439 @@ pub enum AgentSubcommand {
     RunWindowsService,
 }
 
-fn agent_command(agent_subcommand: AgentSubcommand) -> anyhow::Result<()> {
-    let agent_service_manager = SystemService::new(Agent::SERVICE_NAME.into());
+/// Arguments the agent service is always started with, which can't be
+/// passed again as extra install arguments.
+const RESERVED_SERVICE_ARGS: [&str; 3] = ["agent", "run-windows-service", "--instance"];
+
+/// Output format of command results.
+#[derive(clap::ArgEnum, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    /// Human readable messages.
+    Text,
+    /// One JSON object per result, for scripting.
+    Json,
+}
+
+/// How command results are printed.
+#[derive(Clone, Copy, Debug)]
+struct Output {
+    format: OutputFormat,
+    /// Suppress progress messages.
+    quiet: bool,
+}
+
+impl Output {
+    /// Print a progress message, only shown in text mode when not quiet.
+    fn progress(self, message: impl Display) {
+        if self.format == OutputFormat::Text && !self.quiet {
+            println!("{}", message);
+        }
+    }
+
+    /// Print the outcome of an agent lifecycle `action`. Text mode prints
+    /// `message`, if any, JSON mode prints an event object.
+    fn outcome(self, action: &str, result: &str, message: Option<&str>) {
+        match self.format {
+            OutputFormat::Text => {
+                if let Some(message) = message {
+                    println!("{}", message);
+                }
+            },
+            OutputFormat::Json => {
+                println!("{}", serde_json::json!({ "action": action, "result": result, "service": Agent::service_name() }));
+            },
+        }
+    }
+}
+
+/// Service start types selectable from the command line.
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+pub enum StartType {
+    /// Start automatically on boot.
+    Auto,
+    /// Start only when requested.
+    Manual,
+    /// Never start.
+    Disabled,
+}
+
+impl From<StartType> for ServiceStartType {
+    fn from(start_type: StartType) -> Self {
+        match start_type {
+            StartType::Auto => ServiceStartType::AutoStart,
+            StartType::Manual => ServiceStartType::OnDemand,
+            StartType::Disabled => ServiceStartType::Disabled,
+        }
+    }
+}
+
+/// Service SID types selectable from the command line.
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+pub enum SidType {
+    /// Add a write-restricted service SID.
+    Restricted,
+    /// Add the service SID.
+    Unrestricted,
+    /// Do not add a service SID.
+    None,
+}
+
+impl From<SidType> for ServiceSidType {
+    fn from(sid_type: SidType) -> Self {
+        match sid_type {
+            SidType::Restricted => ServiceSidType::Restricted,
+            SidType::Unrestricted => ServiceSidType::Unrestricted,
+            SidType::None => ServiceSidType::None,
+        }
+    }
+}
+
+impl AgentSubcommand {
+    /// Returns true if the subcommand changes the service manager state and
+    /// needs an elevated process.
+    fn requires_elevation(&self) -> bool {
+        matches!(self,
+            AgentSubcommand::Install { .. } | AgentSubcommand::Uninstall { .. } | AgentSubcommand::Start | AgentSubcommand::Stop | AgentSubcommand::Purge { .. } | AgentSubcommand::SetDisplayName { .. } | AgentSubcommand::ImportConfig { .. })
+    }
+}
+
+fn agent_command(agent_subcommand: AgentSubcommand, machine: Option<String>, wait_timeout: Duration, poll_interval: Duration, output: Output) -> anyhow::Result<()> {
+    if machine.is_some() && matches!(agent_subcommand, AgentSubcommand::Foreground | AgentSubcommand::Run | AgentSubcommand::RunWindowsService) {
+        return Err(anyhow::anyhow!("the agent can only run on the local machine, '--machine' can't be used here"));
+    }
+
+    // Fail early rather than with a confusing access denied error from the
+    // service manager. If elevation can't be determined, let the service
+    // manager decide. Remote machines check the network credentials
+    // instead, which local elevation says nothing about.
+    if machine.is_none() && agent_subcommand.requires_elevation() && !win32::is_elevated().unwrap_or(true) {
+        return Err(anyhow::anyhow!("this command requires an elevated (Administrator) prompt"));
+    }
+
+    let machine_is_local = machine.is_none();
+    let agent_service_manager = SystemService::new(Agent::service_name()).on_machine(machine).with_poll_interval(poll_interval);
 
     match agent_subcommand {
-        AgentSubcommand::Install => {
-            println!("Installing Porcelet agent service...");
+        AgentSubcommand::Install { depends_on, start_type, sid_type, start_now, gmsa, extra_args } => {
+            if let Some(arg) = extra_args.iter().find(|arg| RESERVED_SERVICE_ARGS.contains(&arg.as_str()) || arg.starts_with("--instance=")) {
+                return Err(anyhow::anyhow!("'{}' is passed to the agent service already and can't be added with '--arg'", arg));
+            }
 
-            let service_desc = ServiceDescription {
-                friendly_name: Agent::SERVICE_DISPLAY_NAME.into(),
-                binary_path: std::env::current_exe()?.into(),
-                args: vec![OsString::from("agent"), OsString::from("run-windows-service")],
-            };
+            output.progress("Installing Porcelet agent service...");
 
-            agent_service_manager.install(service_desc)?;
+            let mut builder = ServiceDescription::builder()
+                .friendly_name(Agent::service_display_name())
+                .binary_path(std::env::current_exe()?)
+                .arg("agent")
+                .arg("run-windows-service")
+                .args(extra_args)
+                .dependencies(depends_on)
+                .start_type(start_type.into())
+                .sid_type(sid_type.into());
+            if let Some(instance) = Agent::instance() {
+                builder = builder.arg("--instance").arg(instance);
+            }
+            if let Some(gmsa) = gmsa {
+                if !gmsa.ends_with('$') {
+                    return Err(anyhow::anyhow!("group managed service account names end in '$', use 'DOMAIN\\name$'"));
+                }
+                builder = builder.account(gmsa, None);
+            }
+            let service_desc = builder.build()?;
+
+            match agent_service_manager.install(service_desc)? {
+                Installed::Created(_) => output.outcome("install", "created", Some("Porcelet agent service installed.")),
+                Installed::Updated(_) => output.outcome("install", "updated", Some("Porcelet agent service updated configuration.")),
+            }
+
+            if start_now {
+                output.progress("Starting Porcelet agent service...");
+                agent_service_manager.start_and_wait(wait_timeout)?;
+                output.outcome("start", "running", None);
+            }
         },
 
-        AgentSubcommand::Uninstall => {
-            println!("Removing Porcelet agent service...");
-            agent_service_manager.uninstall()?;
+        AgentSubcommand::Uninstall { wait } => {
+            output.progress("Removing Porcelet agent service...");
+            agent_service_manager.uninstall(wait.map(Duration::from_secs))?;
+            output.outcome("uninstall", "removed", None);
         },
 
         AgentSubcommand::Start => {
-            println!("Starting Porcelet agent service...");
+            // Starting from a freshly built binary still runs the installed
+            // one, which is easy to miss. The path means nothing remotely.
+            if machine_is_local {
+                if let (Ok(description), Ok(exe)) = (agent_service_manager.description(), std::env::current_exe()) {
+                    if !description.runs_binary(&exe) {
+                        tracing::warn!("The service will start the previously installed binary at {}, not this one at {}, reinstall to use this one",
+                            description.binary_path.display(), exe.display());
+                    }
+                }
+            }
+            output.progress("Starting Porcelet agent service...");
             agent_service_manager.start()?;
+            output.outcome("start", "requested", None);
         },
 
         AgentSubcommand::Stop => {
-            println!("Stopping Porcelet agent service...");
+            output.progress("Stopping Porcelet agent service...");
             agent_service_manager.stop()?;
+            output.outcome("stop", "requested", None);
         },
 
-        AgentSubcommand::Run => {
-            Runtime::new()?.block_on(async {
-                Agent::new().run().await
-            })?;
+        AgentSubcommand::Purge { force } => purge_agent(&agent_service_manager, force, wait_timeout, output)?,
+
+        AgentSubcommand::SetDisplayName { name } => {
+            output.progress("Updating Porcelet agent service display name...");
+            agent_service_manager.set_display_name(name)?;
+            output.outcome("set-display-name", "updated", None);
         },
 
+        AgentSubcommand::ExportConfig { output } => {
+            let exported = ExportedService::from_description(&agent_service_manager.description()?)?;
+            let json = serde_json::to_string_pretty(&exported)?;
+            match output {
+                Some(output) => std::fs::write(&output, json).with_context(|| format!("failed to write {}", output.display()))?,
+                None => println!("{}", json),
+            }
+        },
+
+        AgentSubcommand::ImportConfig { file, password } => {
+            let json = std::fs::read_to_string(&file).with_context(|| format!("failed to read {}", file.display()))?;
+            let exported: ExportedService = serde_json::from_str(&json).with_context(|| format!("invalid service configuration in {}", file.display()))?;
+
+            output.progress("Installing Porcelet agent service...");
+            match agent_service_manager.install(exported.into_description(password)?)? {
+                Installed::Created(_) => output.outcome("import", "created", Some("Porcelet agent service installed.")),
+                Installed::Updated(_) => output.outcome("import", "updated", Some("Porcelet agent service updated configuration.")),
+            }
+        },
+
+        AgentSubcommand::CheckConfig { file } => {
+            let json = std::fs::read_to_string(&file).with_context(|| format!("failed to read {}", file.display()))?;
+            let desired: ExportedService = serde_json::from_str(&json).with_context(|| format!("invalid service configuration in {}", file.display()))?;
+            let actual = ExportedService::from_description(&agent_service_manager.description()?)?;
+
+            let drift = desired.drift(&actual);
+            if drift.is_empty() {
+                output.outcome("check-config", "in-sync", Some("Porcelet agent service configuration matches."));
+                return Ok(());
+            }
+            match output.format {
+                OutputFormat::Text => {
+                    println!("Porcelet agent service configuration drifted:");
+                    for field in &drift {
+                        println!("  {}: desired {}, installed {}", field.field, field.desired, field.actual);
+                    }
+                },
+                OutputFormat::Json => println!("{}", serde_json::json!({ "action": "check-config", "result": "drift", "service": Agent::service_name(), "drift": drift })),
+            }
+            return Err(ConfigDrifted (drift.len()).into());
+        },
+
+        AgentSubcommand::DumpConfig => dump_config(&agent_service_manager)?,
+
+        AgentSubcommand::Foreground => {
+            tracing::info!(pipe = %Agent::service_pipe(), "Running Porcelet agent in the foreground, press Ctrl-C to stop");
+            run_foreground()?;
+        },
+
+        AgentSubcommand::Run => run_foreground()?,
+
         AgentSubcommand::RunWindowsService => {
-            service_dispatcher::start(&Agent::SERVICE_NAME, ffi_service_main)?;
+            match service_dispatcher::start(Agent::service_name(), ffi_service_main) {
+                // ERROR_FAILED_SERVICE_CONTROLLER_CONNECT, the process was not
+                // started by the service manager.
+                Err(windows_service::Error::Winapi(err)) if err.raw_os_error() == Some(1063) => return Err(NotLaunchedByServiceManager.into()),
+                result => result?,
+            }
         },
     }
 
     Ok(())
 }
 
-async fn agent_status() -> anyhow::Result<()> {
-    let agent_service_manager = SystemService::new(Agent::SERVICE_NAME.into());
+/// Print every field of the agent service configuration.
+fn dump_config(agent_service_manager: &SystemService) -> anyhow::Result<()> {
+    let config = agent_service_manager.raw_config()?;
+    let or_none = |value: Option<&OsStr>| value.map(|value| value.to_string_lossy().into_owned()).unwrap_or_else(|| "(none)".to_string());
+    let dependencies: Vec<String> = config.dependencies.iter().map(|dependency| format!("{:?}", dependency)).collect();
+
+    println!("Service name:     {}", Agent::service_name());
+    println!("Display name:     {}", config.display_name.to_string_lossy());
+    println!("Command line:     {}", config.executable_path.to_string_lossy());
+    println!("Service type:     {:?}", config.service_type);
+    println!("Start type:       {:?}", config.start_type);
+    println!("Error control:    {:?}", config.error_control);
+    println!("Account:          {}", or_none(config.account_name.as_deref()));
+    println!("Dependencies:     {}", if dependencies.is_empty() { "(none)".to_string() } else { dependencies.join(", ") });
+    println!("Load order group: {}", or_none(config.load_order_group.as_deref()));
+    println!("Tag:              {}", config.tag_id);
+    println!("Service SID type: {:?}", agent_service_manager.sid_type()?);
+    Ok(())
+}
+
+/// Run the agent as a plain process until Ctrl-C or Ctrl-Break.
+fn run_foreground() -> anyhow::Result<()> {
+    Runtime::new()?.block_on(async {
+        let mut agent = Agent::new();
+
+        // Drain the agent on Ctrl-C or Ctrl-Break the same way the service
+        // does on stop.
+        let mut ctrl_break = tokio::signal::windows::ctrl_break()?;
+        let shutdown_handle = agent.shutdown_handle();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => tracing::info!("Received Ctrl-C, shutting down"),
+                _ = ctrl_break.recv() => tracing::info!("Received Ctrl-Break, shutting down"),
+            }
+            shutdown_handle.request_shutdown();
+        });
+
+        agent.run().await
+    })
+}
+
+/// Stop and delete the agent service, killing its process if `force` is set
+/// and it does not stop within `wait_timeout`. Each step is reported as it
+/// happens.
+fn purge_agent(agent_service_manager: &SystemService, force: bool, wait_timeout: Duration, output: Output) -> anyhow::Result<()> {
+    let status = agent_service_manager.status()?;
+    if status == ServiceStatus::Uninstalled {
+        output.outcome("purge", "not-installed", Some("Porcelet agent service is not installed."));
+        return Ok(());
+    }
 
-    let service_status = agent_service_manager.status()?;
+    if status != ServiceStatus::Stopped {
+        output.progress("Stopping Porcelet agent service...");
+        if let Err(err) = agent_service_manager.stop() {
+            output.progress(format_args!("  Stop request failed: {}", err));
+        }
+
+        match agent_service_manager.wait_for_stop(wait_timeout) {
+            Ok(()) => output.progress("  Stopped."),
+            Err(err) => {
+                output.progress(format_args!("  Service did not stop: {}", err));
+                if !force {
+                    return Err(anyhow::anyhow!("agent service is still running, rerun with '--force' to kill its process"));
+                }
+
+                output.progress("Killing Porcelet agent process...");
+                match agent_service_manager.kill()? {
+                    Some(pid) => output.progress(format_args!("  Killed process {}.", pid)),
+                    None => output.progress("  Service has no process."),
+                }
+                agent_service_manager.wait_for_stop(wait_timeout)?;
+                output.progress("  Stopped.");
+            },
+        }
+    }
+
+    output.progress("Removing Porcelet agent service...");
+    match agent_service_manager.uninstall(None) {
+        Ok(()) => output.outcome("purge", "removed", Some("  Removed.")),
+        Err(ServiceError::MarkedForDeletion) => output.outcome("purge", "marked-for-deletion", Some("  Already marked for deletion, it will be removed once all open handles to it are closed.")),
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
+/// Describe the service controls in `controls` as a readable list.
+fn describe_controls(controls: ServiceControlAccept) -> String {
+    let names = [
+        (ServiceControlAccept::STOP, "stop"),
+        (ServiceControlAccept::PAUSE_CONTINUE, "pause/continue"),
+        (ServiceControlAccept::PARAM_CHANGE, "parameter change"),
+        (ServiceControlAccept::PRESHUTDOWN, "preshutdown"),
+        (ServiceControlAccept::SHUTDOWN, "shutdown"),
+    ];
+    let accepted: Vec<&str> = names.iter().filter(|(control, _)| controls.contains(*control)).map(|(_, name)| *name).collect();
+    if accepted.is_empty() {
+        "none".to_string()
+    } else {
+        accepted.join(", ")
+    }
+}
+
+/// Print the agent service and pipe status. `quiet` leaves out everything
+/// but the service state and the counter.
+async fn agent_status(quiet: bool) -> anyhow::Result<()> {
+    let agent_service_manager = SystemService::new(Agent::service_name());
+
+    let service_status = agent_service_manager.status_async().await?;
     match service_status {
         ServiceStatus::Uninstalled => println!("Porcelet agent service is not installed."),
-        ServiceStatus::Stopped => println!("Porcelet agent service is not running."),
+        ServiceStatus::Stopped => {
+            println!("Porcelet agent service is not running.");
+            if !quiet {
+                match agent_service_manager.last_exit_code_async().await? {
+                    ServiceExitCode::Win32(0) => println!("  Last exit code: 0 (clean stop)"),
+                    ServiceExitCode::Win32(code) => println!("  Last exit code: {} (failed)", code),
+                    ServiceExitCode::ServiceSpecific(code) => println!("  Last exit code: service specific {} (failed)", code),
+                }
+            }
+        },
+        ServiceStatus::StartPending => println!("Porcelet agent service is starting."),
+        ServiceStatus::StopPending => println!("Porcelet agent service is stopping."),
+        ServiceStatus::ContinuePending => println!("Porcelet agent service is resuming."),
+        ServiceStatus::PausePending => println!("Porcelet agent service is pausing."),
+        ServiceStatus::Paused => println!("Porcelet agent service is paused."),
         ServiceStatus::Running => {},
     }
 
+    if !quiet && !matches!(service_status, ServiceStatus::Uninstalled | ServiceStatus::Stopped) {
+        println!("  Accepts: {}", describe_controls(agent_service_manager.controls_accepted_async().await?));
+    }
+
+    if !quiet && service_status != ServiceStatus::Uninstalled {
+        let description = agent_service_manager.description_async().await?;
+        let account = description.account_name.as_deref().map(|account| account.to_string_lossy()).unwrap_or("LocalSystem".into());
+        if description.runs_as_local_system() {
+            println!("  Running as: {} (full system privileges)", account);
+        } else {
+            println!("  Running as: {}", account);
+        }
+        match description.installed_at {
+            Some(installed_at) => println!("  Installed: {}", format_utc(installed_at)),
+            None => println!("  Installed: unknown"),
+        }
+    }
+
     // Query the service even if the service manager states it is not running,
     // for testing purposes, but don't report an error unless it expected to
     // be running.
-    match Agent::query_status().await {
-        Ok(status) => {
-            if service_status != ServiceStatus::Running {
-                log::warn!("Agent is running outside of the system service manager, this should only happen in testing");
+    match Agent::try_query_status().await {
+        Ok(Some(status)) => {
+            if matches!(service_status, ServiceStatus::Uninstalled | ServiceStatus::Stopped) {
+                tracing::warn!("Agent is running outside of the system service manager, this should only happen in testing");
+            }
+            if !quiet {
+                match Agent::query_capabilities().await {
+                    Ok(capabilities) if !capabilities.endpoints.is_empty() => {
+                        for endpoint in capabilities.endpoints {
+                            println!("  Endpoint: {}", endpoint);
+                        }
+                    },
+                    // Agents that don't report their endpoints answered on
+                    // the pipe of the instance selected with `--instance`.
+                    Ok(_) | Err(ClientError::Protocol(ProtocolError::ConnectionClosed)) => println!("  Endpoint: pipe:{}", Agent::service_pipe()),
+                    Err(err) => return Err(err.into()),
+                }
             }
             println!("  Counter: {}", status);
             Ok(())
         },
+        Ok(None) => {
+            if service_status == ServiceStatus::Running {
+                Err(anyhow::anyhow!("agent service is running but is not serving its pipe"))
+            } else {
+                Ok(())
+            }
+        },
         Err(err) =>  {
             if service_status == ServiceStatus::Running {
-                Err(err)
+                Err(err.into())
             } else {
                 Ok(())
             }
@@ -124,31 +724,321 @@ async fn agent_status() -> anyhow::Result<()> {
     }
 }
 
+async fn agent_metrics() -> anyhow::Result<()> {
+    let metrics = Agent::query_metrics().await?;
+    print!("{}", metrics);
+    Ok(())
+}
+
+/// Print the security descriptor of the agent pipe.
+/// 
+/// The agent creates its pipe with the default security descriptor, which
+/// is described instead if the pipe can't be reached.
+fn pipe_acl() -> anyhow::Result<()> {
+    let sddl = match win32::object_security_sddl(&Agent::service_pipe()) {
+        Ok(sddl) => sddl,
+        // ERROR_FILE_NOT_FOUND and ERROR_PIPE_BUSY.
+        Err(err) if matches!(err.raw_os_error(), Some(2) | Some(231)) => {
+            println!("Agent pipe could not be reached ({}), showing the expected policy.", err);
+            println!("The agent uses the default named pipe security descriptor:");
+            println!("  Allow LocalSystem, Administrators, and the creator owner: full control");
+            println!("  Allow Everyone and Anonymous: read");
+            return Ok(());
+        },
+        Err(err) => return Err(anyhow::Error::new(err).context("failed to read the agent pipe security descriptor")),
+    };
+
+    println!("SDDL: {}", sddl);
+    for line in sddl::describe(&sddl) {
+        println!("  {}", line);
+    }
+    Ok(())
+}
+
+/// Open one connection to the agent and make the version and counter
+/// requests over it, printing how long each step took.
+/// 
+/// Unlike `doctor` this only looks at the live protocol, any failing step
+/// fails the command.
+async fn connect_test() -> anyhow::Result<()> {
+    let started = Instant::now();
+    let mut connection = Agent::connect()?;
+    println!("Connected:  {} ({:.1?})", Agent::service_pipe(), started.elapsed());
+
+    let started = Instant::now();
+    let version = match Agent::exchange(&mut connection, &AgentRequest::Version).await? {
+        AgentResponse::VersionInfo(version) => version,
+        response => return Err(ClientError::from(ProtocolError::unexpected(&response)).into()),
+    };
+    println!("Version:    {} ({:.1?})", version, started.elapsed());
+    if version.version != VersionInfo::current().version {
+        tracing::warn!(cli = %VersionInfo::current().version, agent = %version.version, "Agent and CLI versions differ");
+    }
+
+    let started = Instant::now();
+    let counter = match Agent::exchange(&mut connection, &AgentRequest::GetCounter).await? {
+        AgentResponse::Counter(counter) => counter,
+        response => return Err(ClientError::from(ProtocolError::unexpected(&response)).into()),
+    };
+    println!("Counter:    {} ({:.1?})", counter, started.elapsed());
+    Ok(())
+}
+
+/// Send `request` from `concurrency` clients until `duration` has passed or
+/// `count` requests were sent, then print the throughput and latencies.
+/// 
+/// Connecting while every pipe instance is busy is retried and reported
+/// separately, it means the agent needs more listeners. Any other failure,
+/// or a request taking longer than `timeout_secs`, stops the benchmark.
+async fn bench(request: AgentRequest, duration: Duration, count: Option<u64>, concurrency: u64, timeout_secs: u64) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let remaining = Arc::new(AtomicU64::new(count.unwrap_or(u64::MAX)));
+    let busy = Arc::new(AtomicU64::new(0));
+
+    let clients: Vec<_> = (0..concurrency).map(|_| {
+        let request = request.clone();
+        let remaining = remaining.clone();
+        let busy = busy.clone();
+        tokio::spawn(async move {
+            let mut latencies = Vec::new();
+            while started.elapsed() < duration && remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| remaining.checked_sub(1)).is_ok() {
+                loop {
+                    let sent = Instant::now();
+                    match tokio::time::timeout(Duration::from_secs(timeout_secs), Agent::request(request.clone())).await {
+                        Ok(Ok(_)) => {
+                            latencies.push(sent.elapsed());
+                            break;
+                        },
+                        // ERROR_PIPE_BUSY, every instance is in use.
+                        Ok(Err(ClientError::Transport { source, .. })) if source.raw_os_error() == Some(231) => {
+                            busy.fetch_add(1, Ordering::Relaxed);
+                            tokio::time::sleep(Duration::from_millis(1)).await;
+                        },
+                        Ok(Err(err)) => return Err(anyhow::Error::new(err)),
+                        Err(_) => return Err(AgentTimeout(timeout_secs).into()),
+                    }
+                }
+            }
+            Ok(latencies)
+        })
+    }).collect();
+
+    let mut latencies = Vec::new();
+    for client in clients {
+        latencies.extend(client.await??);
+    }
+    let elapsed = started.elapsed();
+    if latencies.is_empty() {
+        return Err(anyhow::anyhow!("no requests completed"));
+    }
+
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+    println!("Requests:   {} in {:.1?} ({:.0}/s)", latencies.len(), elapsed, latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("Latency:    p50 {:.1?}, p99 {:.1?}, max {:.1?}", percentile(0.5), percentile(0.99), latencies[latencies.len() - 1]);
+    println!("Pipe busy:  {} retries", busy.load(Ordering::Relaxed));
+    Ok(())
+}
+
+async fn agent_sessions(kill: Option<u64>) -> anyhow::Result<()> {
+    if let Some(id) = kill {
+        Agent::kill_session(id).await?;
+        println!("Killed session {}.", id);
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    println!("{:>8}  {:>8}  {:>10}", "ID", "PID", "CONNECTED");
+    for session in Agent::query_sessions().await? {
+        let pid = session.client_pid.map(|pid| pid.to_string()).unwrap_or_else(|| "-".to_string());
+        println!("{:>8}  {:>8}  {:>9}s", session.id, pid, now.saturating_sub(session.started));
+    }
+    Ok(())
+}
+
+/// Print the agent configuration as JSON, from the running agent if
+/// `running` is set.
+fn config_show(timeout_secs: u64, running: bool) -> anyhow::Result<()> {
+    if running {
+        return pipe_command(timeout_secs, async {
+            let config = Agent::query_config().await?;
+            println!("{}", serde_json::to_string_pretty(&config)?);
+            Ok(())
+        });
+    }
+
+    let config = AgentConfig::load().context("failed to load the agent configuration")?;
+    println!("{}", serde_json::to_string_pretty(&config.redacted())?);
+    Ok(())
+}
+
+/// The installed service configuration differs from the desired one.
+#[derive(Error, Debug)]
+#[error("{0} service configuration fields drifted")]
+struct ConfigDrifted (usize);
+
+/// `run-windows-service` was run directly rather than by the service
+/// manager.
+#[derive(Error, Debug)]
+#[error("this command must be launched by the Service Control Manager; use 'agent run' for foreground testing")]
+struct NotLaunchedByServiceManager;
+
+/// The agent did not respond within the CLI timeout.
+#[derive(Error, Debug)]
+#[error("agent did not respond within {0} seconds")]
+struct AgentTimeout (u64);
+
+/// Run a command that talks to the agent over its pipe, failing with
+/// `AgentTimeout` if it does not complete within `timeout_secs`.
+fn pipe_command<F: Future<Output = anyhow::Result<()>>>(timeout_secs: u64, command: F) -> anyhow::Result<()> {
+    let runtime = Runtime::new()?;
+    runtime.block_on(async {
+        match tokio::time::timeout(Duration::from_secs(timeout_secs), command).await {
+            Ok(result) => result,
+            Err(_) => Err(AgentTimeout(timeout_secs).into()),
+        }
+    })
+}
+
 /// Porcelet CLI entry point.
 /// 
-/// If args is None, args are parsed from the command line.
+/// If args is None, args are parsed from the command line. Exits the
+/// process with the code from `run_cli`, or from `report_error` if it
+/// fails.
 pub fn cli_main(args: Option<CliArgs>) -> ! {
-    let args = args.unwrap_or(CliArgs::parse());
+    let args = args.unwrap_or_else(CliArgs::parse);
+    match run_cli(args) {
+        Ok(exit_code) => exit_code.exit(),
+        Err(err) => report_error(&err).exit(),
+    }
+}
+
+/// Run the CLI command given by `args` and return the code the process
+/// should exit with, without exiting.
+/// 
+/// Errors are returned as is, use `report_error` to print them the way the
+/// CLI does and get their exit code.
+pub fn run_cli(args: CliArgs) -> anyhow::Result<ExitCode> {
+    Agent::select_instance(args.instance.clone());
+    let timeout = args.timeout;
+    let output = Output { format: args.format, quiet: args.quiet };
 
     let result = match args.subcommand {
-        CliSubcommand::Agent { agent_subcommand } => agent_command(agent_subcommand),
-        CliSubcommand::Status => {
-            match Runtime::new() {
-                Ok(runtime) => {
-                    runtime.block_on(async {
-                        agent_status().await
-                    })
-                },
-                Err(err) => Err(anyhow::anyhow!(err)),
-            }
+        CliSubcommand::Agent { machine, wait_timeout, poll_interval, agent_subcommand } => {
+            agent_command(agent_subcommand, machine, Duration::from_secs(wait_timeout), Duration::from_millis(poll_interval), output)
+        },
+        CliSubcommand::Status { tcp_port: Some(port), .. } => {
+            pipe_command(timeout, async {
+                let counter = Agent::query_status_tcp(port).await?;
+                if !output.quiet {
+                    println!("  Endpoint: tcp:127.0.0.1:{}", port);
+                }
+                println!("  Counter: {}", counter);
+                Ok(())
+            })
+        },
+        CliSubcommand::Status { wait_ready, tcp_port: None } => {
+            // Not under pipe_command, whose timeout would cut the wait short
+            // with AgentTimeout before it could report NotReady.
+            let ready = if wait_ready {
+                Runtime::new()?.block_on(async {
+                    Agent::wait_until_ready(Duration::from_secs(timeout)).await?;
+                    Ok(())
+                })
+            } else {
+                Ok(())
+            };
+            ready.and_then(|_| pipe_command(timeout, agent_status(output.quiet)))
+        },
+        CliSubcommand::Metrics => pipe_command(timeout, agent_metrics()),
+        CliSubcommand::Sessions { kill } => pipe_command(timeout, agent_sessions(kill)),
+        CliSubcommand::ResetCounter => {
+            pipe_command(timeout, async {
+                let previous = Agent::reset_counter().await?;
+                println!("Counter reset, previous value was {}.", previous);
+                Ok(())
+            })
+        },
+        CliSubcommand::ReserveCounter { count } => {
+            pipe_command(timeout, async {
+                let start = Agent::reserve_counter(count).await?;
+                println!("Reserved counter values {} to {}.", start, start + (count - 1));
+                Ok(())
+            })
+        },
+        CliSubcommand::Config { config_subcommand: ConfigSubcommand::Show { running } } => config_show(timeout, running),
+        CliSubcommand::Doctor => pipe_command(timeout, doctor::run_doctor()),
+        CliSubcommand::Eventlog { count } => eventlog::print_recent(count),
+        CliSubcommand::DumpState => {
+            pipe_command(timeout, async {
+                let dump = Agent::dump_state().await?;
+                println!("{}", serde_json::to_string_pretty(&dump)?);
+                Ok(())
+            })
+        },
+        CliSubcommand::PipeAcl => pipe_acl(),
+        CliSubcommand::ConnectTest => pipe_command(timeout, connect_test()),
+        CliSubcommand::Repl => Runtime::new()?.block_on(repl::run_repl(Duration::from_secs(timeout))),
+        CliSubcommand::Bench { duration, count, concurrency, counter } => {
+            let request = if counter { AgentRequest::NextCounter } else { AgentRequest::Version };
+            Runtime::new()?.block_on(bench(request, Duration::from_secs(duration), count, concurrency, timeout))
+        },
+        CliSubcommand::Version { agent: false } => {
+            println!("porcelet {}", VersionInfo::current());
+            Ok(())
+        },
+        CliSubcommand::Version { agent: true } => {
+            pipe_command(timeout, async {
+                let version = Agent::query_version().await?;
+                println!("porcelet agent {}", version);
+                Ok(())
+            })
         },
     };
 
-    match result {
-        Ok(_) => std::process::exit(0),
-        Err(err) => {
-            log::error!("Error: {}", err);
-            std::process::exit(1)
+    result.map(|_| ExitCode::Success)
+}
+
+/// Log a failed command's error, print any hint for resolving it, and
+/// return the exit code it maps to.
+pub fn report_error(err: &anyhow::Error) -> ExitCode {
+    tracing::error!("Error: {:#}", err);
+    if err.is::<AgentTimeout>() {
+        return ExitCode::AgentTimeout;
+    }
+    if err.is::<ConfigDrifted>() {
+        return ExitCode::ConfigDrift;
+    }
+    if let Some(ServiceError::ServiceDisabled) = err.downcast_ref::<ServiceError>() {
+        eprintln!("The service is disabled; change its start type with `porcelet agent install --start-type`.");
+        return ExitCode::ServiceDisabled;
+    }
+    if let Some(ServiceError::ServiceStopping) = err.downcast_ref::<ServiceError>() {
+        eprintln!("The service is still stopping; rerun with `--wait <SECONDS>` to remove it once it stops.");
+    }
+    match err.downcast_ref::<ClientError>() {
+        Some(ClientError::AccessDenied) => {
+            eprintln!("The agent pipe only allows some users to connect, try again from an elevated (Administrator) prompt.");
+            ExitCode::AgentUnavailable
+        },
+        Some(ClientError::AgentNotRunning) => {
+            eprintln!("Start the agent with `porcelet agent start`.");
+            ExitCode::AgentUnavailable
+        },
+        Some(ClientError::Transport { .. }) | Some(ClientError::NotReady(_)) | Some(ClientError::NotResponding(_)) => ExitCode::AgentUnavailable,
+        Some(ClientError::ShuttingDown) => {
+            eprintln!("Start the agent again with `porcelet agent start` once it has stopped.");
+            ExitCode::AgentUnavailable
+        },
+        Some(ClientError::Protocol(_)) => {
+            eprintln!("The agent and CLI may be different versions, compare `porcelet version` with `porcelet version --agent`.");
+            ExitCode::ProtocolError
+        },
+        Some(ClientError::Agent(_)) => ExitCode::AgentRejected,
+        Some(ClientError::Unsupported(_)) => {
+            eprintln!("Upgrade the agent to use this command, compare `porcelet version` with `porcelet version --agent`.");
+            ExitCode::AgentRejected
         },
+        None => ExitCode::Failure,
     }
 }
@@ -0,0 +1,436 @@
+use std::{fmt::Display, io, path::PathBuf, str::FromStr, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{agent::Agent, protocol, win32};
+
+/// Porcelet agent configuration.
+///
+/// The agent service reads its configuration from the service `Parameters`
+/// registry key and `PORCELET_` environment variables, see
+/// `AgentConfig::load()`. Values that are not set use the defaults.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AgentConfig {
+    /// Size, in bytes, of the input buffer of each named pipe instance.
+    ///
+    /// The buffer is allocated from non-paged pool for every pipe instance,
+    /// so large values cost memory per connection. Small values force a
+    /// writer to wait for the reader to drain the pipe more often, which
+    /// lowers throughput when large payloads are sent.
+    pub in_buffer_size: u32,
+
+    /// Size, in bytes, of the output buffer of each named pipe instance.
+    ///
+    /// The same tradeoff as `in_buffer_size` applies, but for data sent by
+    /// the agent to clients, such as streamed command output.
+    pub out_buffer_size: u32,
+
+    /// Maximum number of new connections accepted per second, zero for no
+    /// limit.
+    ///
+    /// Connections over the limit are not refused outright, the agent stops
+    /// serving new connections until the rate drops back under the limit.
+    /// Clients can still connect to the listening pipe instances meanwhile,
+    /// see `listener_count`.
+    pub max_connections_per_second: u32,
+
+    /// Time a client has to finish sending a request once it has started.
+    ///
+    /// A client that stalls part way through a request is sent
+    /// `AgentResponse::Timeout` and disconnected. Waiting for a client to
+    /// start its next request is not bounded by this.
+    pub request_read_timeout: Duration,
+
+    /// Full paths of the client executables allowed to connect, empty to
+    /// allow any client.
+    ///
+    /// Clients are matched by the image path of the process on the other end
+    /// of the pipe, compared case-insensitively. Connections from other
+    /// executables, or from clients whose process can't be resolved, are
+    /// closed without being served.
+    pub allowed_client_images: Vec<PathBuf>,
+
+    /// Maximum size, in bytes, of a request body the agent will read.
+    ///
+    /// The length prefix is checked before the body is allocated, a client
+    /// declaring a larger request is disconnected.
+    pub max_message_size: u32,
+
+    /// Interval between self-checks of the agent pipe, `None` to disable
+    /// them.
+    ///
+    /// Each check connects to the agent's own pipe and requests its version,
+    /// which does not touch the counter. If a client allowlist is set, it
+    /// must include the agent executable.
+    pub heartbeat_interval: Option<Duration>,
+
+    /// Exit with an error when a heartbeat check fails, so the service
+    /// manager's recovery actions can restart the agent.
+    pub heartbeat_exit_on_failure: bool,
+
+    /// Number of pipe instances kept listening for clients at the same time,
+    /// at least one.
+    ///
+    /// Each listener replaces its instance as soon as a client connects, so
+    /// a burst of up to this many clients can connect without waiting for
+    /// the agent to create new instances. Every listening instance holds
+    /// its own pipe buffers.
+    pub listener_count: u32,
+
+    /// Also write logs as JSON lines to `file_log::default_path()`.
+    ///
+    /// The file is written in addition to stderr, and only by the agent,
+    /// not by other CLI commands.
+    pub log_file: bool,
+
+    /// Size, in bytes, at which the log file is rotated, zero to never
+    /// rotate it.
+    pub log_file_max_size: u32,
+
+    /// Number of rotated log files kept next to the current one, older
+    /// files are deleted on rotation.
+    pub log_file_retention: u32,
+
+    /// Also serve clients over TCP on this port of `127.0.0.1`, `None` to
+    /// only serve the named pipe.
+    ///
+    /// This is for clients that can't open the pipe. The protocol is the
+    /// same as on the pipe, and so is the authenticator: the client is
+    /// identified by the local process owning the other end of the
+    /// connection. Clients whose process can't be resolved, such as tools
+    /// running in WSL, are refused unless `tcp_allow_unidentified` is set.
+    pub tcp_port: Option<u16>,
+
+    /// Let TCP clients whose process can't be resolved make read-only
+    /// requests, such as status queries.
+    ///
+    /// Tools in WSL with mirrored networking connect from outside any
+    /// Windows process, so `DefaultAuthenticator` can't check who they
+    /// are. Every other request still requires an identified client.
+    pub tcp_allow_unidentified: bool,
+
+    /// Pipe to serve, `None` for the pipe of the selected agent instance,
+    /// `Agent::service_pipe()`.
+    ///
+    /// Not read from the registry or the environment, clients find the
+    /// agent by its instance. This lets an embedded or test agent serve a
+    /// pipe of its own without selecting an instance for the process.
+    pub pipe_name: Option<String>,
+}
+
+impl AgentConfig {
+    /// Default pipe buffer size, matches the tokio `ServerOptions` default.
+    pub const DEFAULT_BUFFER_SIZE: u32 = 65536;
+
+    /// Default connection rate limit.
+    pub const DEFAULT_MAX_CONNECTIONS_PER_SECOND: u32 = 100;
+
+    /// Default number of listening pipe instances.
+    pub const DEFAULT_LISTENER_COUNT: u32 = 8;
+
+    /// Default size at which the log file is rotated.
+    pub const DEFAULT_LOG_FILE_MAX_SIZE: u32 = 10 * 1024 * 1024;
+
+    /// Default number of rotated log files kept.
+    pub const DEFAULT_LOG_FILE_RETENTION: u32 = 5;
+
+    /// Default time to finish sending a request.
+    pub const DEFAULT_REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Registry key, under HKEY_LOCAL_MACHINE, the configuration is read
+    /// from. This is the `Parameters` key of the selected agent instance's
+    /// service.
+    pub fn parameters_key() -> String {
+        format!(r"SYSTEM\CurrentControlSet\Services\{}\Parameters", Agent::service_name())
+    }
+
+    /// Load the configuration.
+    ///
+    /// Layers, from lowest to highest precedence, the defaults, the
+    /// `Parameters` registry key, and `PORCELET_` environment variables,
+    /// then checks the result with `validate()`. Returns an error naming
+    /// the value that could not be parsed or is invalid.
+    pub fn load() -> io::Result<Self> {
+        let mut config = Self::load_registry()?;
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the configuration can be used, returning an error naming
+    /// the first invalid value.
+    pub fn validate(&self) -> io::Result<()> {
+        let positive = [
+            (self.in_buffer_size, "InBufferSize", "PORCELET_IN_BUFFER_SIZE"),
+            (self.out_buffer_size, "OutBufferSize", "PORCELET_OUT_BUFFER_SIZE"),
+            (self.max_message_size, "MaxMessageSize", "PORCELET_MAX_MESSAGE_SIZE"),
+            (self.listener_count, "ListenerCount", "PORCELET_LISTENER_COUNT"),
+        ];
+        for (value, registry_name, env_name) in positive {
+            if value == 0 {
+                return Err(invalid_value(registry_name, env_name, value, "must be greater than zero"));
+            }
+        }
+        if self.request_read_timeout.is_zero() {
+            return Err(invalid_value("RequestReadTimeoutSeconds", "PORCELET_REQUEST_READ_TIMEOUT_MS", 0, "must be greater than zero"));
+        }
+        Ok(())
+    }
+
+    /// Load the configuration from the `Parameters` registry key.
+    ///
+    /// Each field is read from a value named after it, `REG_DWORD` values
+    /// for numbers and flags, with timeouts in seconds and zero to disable
+    /// optional intervals, and `REG_MULTI_SZ` for lists.
+    /// Missing values, or a missing key, use the defaults.
+    fn load_registry() -> io::Result<Self> {
+        let mut config = Self::default();
+        let parameters_key = Self::parameters_key();
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "InBufferSize")? {
+            config.in_buffer_size = value;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "OutBufferSize")? {
+            config.out_buffer_size = value;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "MaxConnectionsPerSecond")? {
+            config.max_connections_per_second = value;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "RequestReadTimeoutSeconds")? {
+            config.request_read_timeout = Duration::from_secs(value.into());
+        }
+        if let Some(value) = win32::local_machine_multi_string(&parameters_key, "AllowedClientImages")? {
+            config.allowed_client_images = value.into_iter().map(PathBuf::from).collect();
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "MaxMessageSize")? {
+            config.max_message_size = value;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "HeartbeatIntervalSeconds")? {
+            config.heartbeat_interval = (value != 0).then(|| Duration::from_secs(value.into()));
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "HeartbeatExitOnFailure")? {
+            config.heartbeat_exit_on_failure = value != 0;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "ListenerCount")? {
+            config.listener_count = value;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "LogFile")? {
+            config.log_file = value != 0;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "LogFileMaxSize")? {
+            config.log_file_max_size = value;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "LogFileRetention")? {
+            config.log_file_retention = value;
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "TcpPort")? {
+            let port = u16::try_from(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid value {} for TcpPort: not a port number", value)))?;
+            config.tcp_port = (port != 0).then_some(port);
+        }
+        if let Some(value) = win32::local_machine_dword(&parameters_key, "TcpAllowUnidentified")? {
+            config.tcp_allow_unidentified = value != 0;
+        }
+        Ok(config)
+    }
+
+    /// Override fields with the environment variables that are set.
+    ///
+    /// Each field is read from `PORCELET_` followed by its name in upper
+    /// case, with timeouts in milliseconds, `PORCELET_REQUEST_READ_TIMEOUT_MS`,
+    /// zero to disable optional settings, `true` or `false` for flags, and
+    /// lists separated like `PATH`.
+    fn apply_env(&mut self) -> io::Result<()> {
+        if let Some(value) = env_value("PORCELET_IN_BUFFER_SIZE")? {
+            self.in_buffer_size = value;
+        }
+        if let Some(value) = env_value("PORCELET_OUT_BUFFER_SIZE")? {
+            self.out_buffer_size = value;
+        }
+        if let Some(value) = env_value("PORCELET_MAX_CONNECTIONS_PER_SECOND")? {
+            self.max_connections_per_second = value;
+        }
+        if let Some(value) = env_value("PORCELET_REQUEST_READ_TIMEOUT_MS")? {
+            self.request_read_timeout = Duration::from_millis(value);
+        }
+        if let Some(value) = std::env::var_os("PORCELET_ALLOWED_CLIENT_IMAGES") {
+            self.allowed_client_images = std::env::split_paths(&value).collect();
+        }
+        if let Some(value) = env_value("PORCELET_MAX_MESSAGE_SIZE")? {
+            self.max_message_size = value;
+        }
+        if let Some(value) = env_value("PORCELET_HEARTBEAT_INTERVAL_MS")? {
+            self.heartbeat_interval = (value != 0).then(|| Duration::from_millis(value));
+        }
+        if let Some(value) = env_value("PORCELET_HEARTBEAT_EXIT_ON_FAILURE")? {
+            self.heartbeat_exit_on_failure = value;
+        }
+        if let Some(value) = env_value("PORCELET_LISTENER_COUNT")? {
+            self.listener_count = value;
+        }
+        if let Some(value) = env_value("PORCELET_LOG_FILE")? {
+            self.log_file = value;
+        }
+        if let Some(value) = env_value("PORCELET_LOG_FILE_MAX_SIZE")? {
+            self.log_file_max_size = value;
+        }
+        if let Some(value) = env_value("PORCELET_LOG_FILE_RETENTION")? {
+            self.log_file_retention = value;
+        }
+        if let Some(value) = env_value("PORCELET_TCP_PORT")? {
+            self.tcp_port = (value != 0).then_some(value);
+        }
+        if let Some(value) = env_value("PORCELET_TCP_ALLOW_UNIDENTIFIED")? {
+            self.tcp_allow_unidentified = value;
+        }
+        Ok(())
+    }
+
+    /// Pipe the agent serves, `pipe_name` or the pipe of the selected
+    /// instance.
+    pub fn pipe(&self) -> String {
+        self.pipe_name.clone().unwrap_or_else(Agent::service_pipe)
+    }
+
+    /// Endpoints the agent serves with this configuration, the pipe and,
+    /// if `tcp_port` is set, loopback TCP.
+    pub fn endpoints(&self) -> Vec<String> {
+        let mut endpoints = vec![format!("pipe:{}", self.pipe())];
+        if let Some(port) = self.tcp_port {
+            endpoints.push(format!("tcp:127.0.0.1:{}", port));
+        }
+        endpoints
+    }
+
+    /// Copy of the configuration that is safe to show to clients, with
+    /// sensitive fields masked.
+    ///
+    /// No fields are sensitive yet, new fields holding secrets must be
+    /// masked here.
+    pub fn redacted(&self) -> Self {
+        self.clone()
+    }
+
+    /// Load the configuration, falling back to the defaults if it can't be
+    /// read.
+    pub fn load_or_default() -> Self {
+        Self::load().unwrap_or_else(|err| {
+            tracing::error!(error = %err, "Failed to load agent configuration, using defaults");
+            Self::default()
+        })
+    }
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            in_buffer_size: Self::DEFAULT_BUFFER_SIZE,
+            out_buffer_size: Self::DEFAULT_BUFFER_SIZE,
+            max_connections_per_second: Self::DEFAULT_MAX_CONNECTIONS_PER_SECOND,
+            request_read_timeout: Self::DEFAULT_REQUEST_READ_TIMEOUT,
+            allowed_client_images: Vec::new(),
+            max_message_size: protocol::DEFAULT_MAX_MESSAGE_SIZE,
+            heartbeat_interval: None,
+            heartbeat_exit_on_failure: false,
+            listener_count: Self::DEFAULT_LISTENER_COUNT,
+            log_file: false,
+            log_file_max_size: Self::DEFAULT_LOG_FILE_MAX_SIZE,
+            log_file_retention: Self::DEFAULT_LOG_FILE_RETENTION,
+            tcp_port: None,
+            tcp_allow_unidentified: false,
+            pipe_name: None,
+        }
+    }
+}
+
+/// Error for a `value` that parsed but can't be used, naming the registry
+/// value and environment variable it may have come from.
+fn invalid_value(registry_name: &str, env_name: &str, value: impl Display, reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid value {} for {} or {}: {}", value, registry_name, env_name, reason))
+}
+
+/// Parse the value of the environment variable `name`, if it is set.
+fn env_value<T: FromStr>(name: &str) -> io::Result<Option<T>> where T::Err: Display {
+    let value = match std::env::var_os(name) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let value = value.to_string_lossy();
+    value.trim().parse().map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid value '{}' for {}: {}", value, name, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_request_read_timeout_is_rejected() {
+        let config = AgentConfig { request_read_timeout: Duration::ZERO, ..AgentConfig::default() };
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("PORCELET_REQUEST_READ_TIMEOUT_MS"), "{}", err);
+        assert!(AgentConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn zero_sizes_and_listener_count_are_rejected() {
+        let configs = [
+            (AgentConfig { in_buffer_size: 0, ..AgentConfig::default() }, "InBufferSize"),
+            (AgentConfig { out_buffer_size: 0, ..AgentConfig::default() }, "OutBufferSize"),
+            (AgentConfig { max_message_size: 0, ..AgentConfig::default() }, "MaxMessageSize"),
+            (AgentConfig { listener_count: 0, ..AgentConfig::default() }, "ListenerCount"),
+        ];
+        for (config, name) in configs {
+            let err = config.validate().unwrap_err();
+            assert!(err.to_string().contains(name), "{}", err);
+        }
+    }
+
+    #[test]
+    fn endpoints_list_the_pipe_and_any_tcp_port() {
+        let config = AgentConfig { pipe_name: Some(r"\\.\pipe\porcelet-test".to_string()), ..AgentConfig::default() };
+        assert_eq!(config.endpoints(), [r"pipe:\\.\pipe\porcelet-test"]);
+        let config = AgentConfig { tcp_port: Some(7070), ..config };
+        assert_eq!(config.endpoints(), [r"pipe:\\.\pipe\porcelet-test", "tcp:127.0.0.1:7070"]);
+    }
+
+    #[test]
+    fn invalid_environment_value_names_the_variable() {
+        std::env::set_var("PORCELET_TEST_INVALID_NUMBER", "12abc");
+        let err = env_value::<u32>("PORCELET_TEST_INVALID_NUMBER").unwrap_err();
+        std::env::remove_var("PORCELET_TEST_INVALID_NUMBER");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("invalid value '12abc' for PORCELET_TEST_INVALID_NUMBER: "), "{}", err);
+    }
+
+    /// The only test setting the `PORCELET_` variables `apply_env` reads,
+    /// so parallel tests don't see each other's values.
+    #[test]
+    fn environment_overrides_registry_values() {
+        // Stands in for the values read from the registry.
+        let registry = AgentConfig { in_buffer_size: 1024, listener_count: 2, ..AgentConfig::default() };
+
+        std::env::set_var("PORCELET_IN_BUFFER_SIZE", "4096");
+        std::env::set_var("PORCELET_HEARTBEAT_INTERVAL_MS", "1500");
+        let mut config = registry.clone();
+        let result = config.apply_env();
+        std::env::remove_var("PORCELET_IN_BUFFER_SIZE");
+        std::env::remove_var("PORCELET_HEARTBEAT_INTERVAL_MS");
+        result.unwrap();
+        assert_eq!(config.in_buffer_size, 4096);
+        assert_eq!(config.heartbeat_interval, Some(Duration::from_millis(1500)));
+        assert_eq!(config.listener_count, 2, "values without a variable keep the registry value");
+
+        std::env::set_var("PORCELET_LISTENER_COUNT", "0");
+        let mut config = registry.clone();
+        let result = config.apply_env().and_then(|()| config.validate());
+        std::env::remove_var("PORCELET_LISTENER_COUNT");
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("PORCELET_LISTENER_COUNT"), "{}", err);
+
+        std::env::set_var("PORCELET_OUT_BUFFER_SIZE", "large");
+        let mut config = registry;
+        let result = config.apply_env();
+        std::env::remove_var("PORCELET_OUT_BUFFER_SIZE");
+        let err = result.unwrap_err();
+        assert!(err.to_string().starts_with("invalid value 'large' for PORCELET_OUT_BUFFER_SIZE: "), "{}", err);
+    }
+}
@@ -0,0 +1,99 @@
+//! Diagnostics for common agent installation problems.
+
+use std::fmt::Display;
+
+use crate::{agent::Agent, eventlog, service::{SystemService, ServiceStatus}, win32};
+
+/// Outcome of a single diagnostic check.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum CheckResult {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// Collects and prints check results.
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn check(&mut self, result: CheckResult, message: impl Display, hint: Option<&str>) {
+        let label = match result {
+            CheckResult::Ok => " OK ",
+            CheckResult::Warn => "WARN",
+            CheckResult::Fail => "FAIL",
+        };
+        println!("[{}] {}", label, message);
+        if let Some(hint) = hint {
+            println!("       {}", hint);
+        }
+        self.failed |= result == CheckResult::Fail;
+    }
+}
+
+/// Run all diagnostic checks, printing a line for each.
+/// 
+/// Returns an error if any check failed.
+pub async fn run_doctor() -> anyhow::Result<()> {
+    let mut report = Report::default();
+
+    match win32::is_elevated() {
+        Ok(true) => report.check(CheckResult::Ok, "Running elevated", None),
+        Ok(false) => report.check(CheckResult::Warn, "Not running elevated", Some("install, uninstall, start, and stop need an elevated (Administrator) prompt")),
+        Err(err) => report.check(CheckResult::Warn, format!("Could not determine elevation: {}", err), None),
+    }
+
+    let agent_service_manager = SystemService::new(Agent::service_name());
+    let service_status = agent_service_manager.status_async().await;
+    match &service_status {
+        Ok(ServiceStatus::Uninstalled) => report.check(CheckResult::Fail, "Agent service is not installed", Some("run 'porcelet agent install' from an elevated prompt")),
+        Ok(ServiceStatus::Stopped) => report.check(CheckResult::Warn, "Agent service is installed but not running", Some("run 'porcelet agent start' from an elevated prompt")),
+        Ok(ServiceStatus::Running) => report.check(CheckResult::Ok, "Agent service is running", None),
+        Ok(status) => report.check(CheckResult::Warn, format!("Agent service is in state {:?}", status), None),
+        Err(err) => report.check(CheckResult::Fail, format!("Could not query the agent service: {}", err), None),
+    }
+
+    if matches!(service_status, Ok(status) if status != ServiceStatus::Uninstalled) {
+        match agent_service_manager.description_async().await {
+            Ok(description) => {
+                let binary_path = description.binary_path.to_string_lossy();
+                if !description.binary_path.exists() {
+                    report.check(CheckResult::Fail, format!("Service binary {} does not exist", binary_path), Some("reinstall the agent with 'porcelet agent install'"));
+                } else if !std::env::current_exe().map(|exe| description.runs_binary(&exe)).unwrap_or(false) {
+                    report.check(CheckResult::Warn, format!("Service binary {} is not this executable", binary_path), Some("the service will run the installed binary, reinstall to use this one"));
+                } else {
+                    report.check(CheckResult::Ok, format!("Service binary is {}", binary_path), None);
+                }
+            },
+            Err(err) => report.check(CheckResult::Fail, format!("Could not read the agent service configuration: {}", err), None),
+        }
+    }
+
+    let service_running = matches!(service_status, Ok(ServiceStatus::Running));
+    match Agent::pipe_is_owned() {
+        Ok(true) if service_running => report.check(CheckResult::Ok, "Agent pipe is owned by a live server", None),
+        Ok(true) => report.check(CheckResult::Warn, "Agent pipe is owned by a live server, but the agent service is not running", Some("a stale or test agent process may own the pipe, check for other porcelet processes")),
+        Ok(false) if service_running => report.check(CheckResult::Fail, "Agent pipe is free, but the agent service is running", Some("the agent may have failed to create its pipe, restart the agent service")),
+        Ok(false) => report.check(CheckResult::Ok, "Agent pipe is free", None),
+        Err(err) => report.check(CheckResult::Warn, format!("Could not check agent pipe ownership: {}", err), None),
+    }
+
+    match Agent::query_version().await {
+        Ok(version) => report.check(CheckResult::Ok, format!("Agent pipe is reachable, agent version {}", version.version), None),
+        Err(err) => report.check(CheckResult::Fail, format!("Agent pipe is not reachable: {:#}", err), Some("check that the agent is running")),
+    }
+
+    match win32::local_machine_key_exists(eventlog::SOURCE_KEY) {
+        Ok(true) => report.check(CheckResult::Ok, "Event log source is registered", None),
+        Ok(false) => report.check(CheckResult::Warn, "Event log source is not registered", Some("agent logs are only written to stderr")),
+        Err(err) => report.check(CheckResult::Warn, format!("Could not check the event log source: {}", err), None),
+    }
+
+    if report.failed {
+        Err(anyhow::anyhow!("one or more checks failed"))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+//! Reading the agent's entries in the Windows Event Log.
+
+use winapi::um::winnt;
+
+use crate::{timestamp::format_utc, win32};
+
+/// Event log source the agent writes as.
+pub const SOURCE: &str = "Porcelet Agent";
+
+/// Registry key of the agent's event log source.
+pub const SOURCE_KEY: &str = r"SYSTEM\CurrentControlSet\Services\EventLog\Application\Porcelet Agent";
+
+/// Print the last `count` entries the agent wrote to the Application log,
+/// oldest first.
+/// 
+/// This only reads the local event log, so it works when the agent is not
+/// running.
+pub fn print_recent(count: usize) -> anyhow::Result<()> {
+    if !win32::local_machine_key_exists(SOURCE_KEY)? {
+        println!("Event log source '{}' is not registered, the agent has not written any events.", SOURCE);
+        return Ok(());
+    }
+
+    let mut entries = win32::read_event_log("Application", SOURCE, count)?;
+    if entries.is_empty() {
+        println!("No '{}' entries in the Application log.", SOURCE);
+        return Ok(());
+    }
+
+    entries.reverse();
+    for entry in entries {
+        let message: Vec<_> = entry.strings.iter().map(|string| string.to_string_lossy()).collect();
+        println!("{}  {:<13}  {:>5}  {}", format_utc(entry.time_generated), level_name(entry.event_type), entry.event_id, message.join(" "));
+    }
+    Ok(())
+}
+
+/// Name of an event log entry level, as Event Viewer shows it.
+fn level_name(event_type: u16) -> &'static str {
+    match event_type {
+        winnt::EVENTLOG_ERROR_TYPE => "Error",
+        winnt::EVENTLOG_WARNING_TYPE => "Warning",
+        winnt::EVENTLOG_INFORMATION_TYPE | winnt::EVENTLOG_SUCCESS => "Information",
+        winnt::EVENTLOG_AUDIT_SUCCESS => "Audit Success",
+        winnt::EVENTLOG_AUDIT_FAILURE => "Audit Failure",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,35 @@
+//! Process and service exit codes.
+
+/// Exit codes reported by the CLI process and by the agent service to the
+/// service manager.
+/// 
+/// Scripts may rely on these values, so existing codes must not change.
+#[repr(u32)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ExitCode {
+    /// The command completed, or the agent stopped cleanly.
+    Success = 0,
+    /// The command failed, or the agent exited with an error.
+    Failure = 1,
+    /// The agent service could not start its tokio runtime.
+    RuntimeFailed = 2,
+    /// The agent did not respond within the CLI timeout.
+    AgentTimeout = 3,
+    /// The agent pipe could not be reached, or failed while in use.
+    AgentUnavailable = 4,
+    /// The agent was reached but did not follow the protocol.
+    ProtocolError = 5,
+    /// The agent handled the request and returned an error.
+    AgentRejected = 6,
+    /// The agent service could not be started because it is disabled.
+    ServiceDisabled = 7,
+    /// The installed service configuration differs from the desired one.
+    ConfigDrift = 8,
+}
+
+impl ExitCode {
+    /// Exit the current process with this code.
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
@@ -0,0 +1,235 @@
+//! JSON lines log file with size-based rotation.
+//!
+//! Each event is written as one JSON object with its timestamp, level,
+//! target, and fields. When the file would grow past its maximum size it is
+//! renamed to `agent.log.1`, older files shift up by one, and files past the
+//! retention count are deleted.
+
+use std::{fmt, fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+
+use serde_json::{Map, Value};
+use tracing::{field::{Field, Visit}, Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{agent::Agent, config::AgentConfig, timestamp::format_utc};
+
+/// Path of the agent log file, `logs\agent.log` in the data directory of
+/// the selected agent instance.
+pub fn default_path() -> PathBuf {
+    Agent::data_dir().join("logs").join("agent.log")
+}
+
+/// Log layer for the agent configuration, `None` if the log file is
+/// disabled.
+///
+/// Logging is not set up yet, so a log file that can't be opened is
+/// reported on stderr and skipped rather than failing the agent.
+pub fn layer_from_config(config: &AgentConfig) -> Option<JsonFileLayer> {
+    if !config.log_file {
+        return None;
+    }
+    let path = default_path();
+    match RotatingFile::open(&path, config.log_file_max_size.into(), config.log_file_retention) {
+        Ok(file) => Some(JsonFileLayer::new(file)),
+        Err(err) => {
+            eprintln!("Failed to open log file '{}': {}", path.display(), err);
+            None
+        },
+    }
+}
+
+/// Append-only file rotated once it reaches a maximum size.
+pub struct RotatingFile {
+    path: PathBuf,
+    /// Size at which the file is rotated, zero to never rotate.
+    max_size: u64,
+    /// Number of rotated files kept.
+    retention: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Open `path` for appending, creating it and its directory if needed.
+    pub fn open(path: &Path, max_size: u64, retention: u32) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), max_size, retention, file, size })
+    }
+
+    /// Path of the `index`th rotated file, `agent.log.1` being the newest.
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    /// Shift the rotated files up by one, deleting the oldest, and start a
+    /// new empty file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let remove = |path: &Path| match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+
+        if self.retention == 0 {
+            remove(&self.path)?;
+        } else {
+            remove(&self.rotated_path(self.retention))?;
+            for index in (1..self.retention).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Append `line`, rotating first if it would take the file past the
+    /// maximum size. A line is never split across files, so a single line
+    /// larger than the maximum gets a file of its own.
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.max_size != 0 && self.size != 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Tracing layer writing every event as a JSON line to a `RotatingFile`.
+pub struct JsonFileLayer {
+    file: Mutex<RotatingFile>,
+}
+
+impl JsonFileLayer {
+    pub fn new(file: RotatingFile) -> Self {
+        Self { file: Mutex::new(file) }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JsonFileLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        let entry = serde_json::json!({
+            "timestamp": format_utc(now),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields,
+        });
+
+        // Errors can't be logged from inside the logger, report them on
+        // stderr instead.
+        let mut line = entry.to_string().into_bytes();
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(err) = file.write_line(&line) {
+            eprintln!("Failed to write log file '{}': {}", file.path.display(), err);
+        }
+    }
+}
+
+/// Collects event fields into a JSON object, keeping numbers and flags
+/// typed and formatting anything else with `Debug`.
+struct JsonVisitor<'a> (&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty scratch directory for one test, removed when dropped.
+    struct TempDir (PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("porcelet-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn read(path: PathBuf) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn write_line_rotates_and_keeps_the_retention_count() {
+        let dir = TempDir::new("rotate");
+        let path = dir.0.join("logs").join("agent.log");
+        let mut file = RotatingFile::open(&path, 6, 2).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(read(path.clone()).as_deref(), Some("four\n"));
+        assert_eq!(read(file.rotated_path(1)).as_deref(), Some("three\n"));
+        assert_eq!(read(file.rotated_path(2)).as_deref(), Some("two\n"));
+        // "one" was rotated past the retention count and deleted.
+        assert_eq!(read(file.rotated_path(3)), None);
+    }
+
+    #[test]
+    fn write_line_keeps_lines_together() {
+        let dir = TempDir::new("whole-lines");
+        let path = dir.0.join("agent.log");
+        let mut file = RotatingFile::open(&path, 8, 1).unwrap();
+        file.write_line(b"abc\n").unwrap();
+        file.write_line(b"a line longer than the maximum\n").unwrap();
+
+        assert_eq!(read(path).as_deref(), Some("a line longer than the maximum\n"));
+        assert_eq!(read(file.rotated_path(1)).as_deref(), Some("abc\n"));
+    }
+
+    #[test]
+    fn zero_retention_truncates_in_place() {
+        let dir = TempDir::new("no-retention");
+        let path = dir.0.join("agent.log");
+        let mut file = RotatingFile::open(&path, 4, 0).unwrap();
+        file.write_line(b"one\n").unwrap();
+        file.write_line(b"two\n").unwrap();
+
+        assert_eq!(read(path).as_deref(), Some("two\n"));
+        assert_eq!(read(file.rotated_path(1)), None);
+    }
+}
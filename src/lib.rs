@@ -0,0 +1,150 @@
+//! Porcelet agent and CLI.
+//!
+//! The `porcelet` binary is a thin wrapper around `cli::run_cli`. Other
+//! binaries can embed the agent with `agent::Agent`, manage its service with
+//! `service::SystemService`, or run CLI commands without exiting the process.
+
+use std::{ffi::OsString, sync::{Arc, OnceLock}, time::Duration};
+
+use agent::{Agent, PowerEvent};
+use exit_code::ExitCode;
+use tokio::runtime::Runtime;
+use windows_service::{define_windows_service, service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle}, service::{PowerEventParam, ServiceControl, ServiceType, ServiceState, ServiceControlAccept, ServiceExitCode}};
+
+pub mod agent;
+pub mod cli;
+pub mod config;
+mod doctor;
+mod eventlog;
+pub mod exit_code;
+pub mod file_log;
+mod metrics;
+pub mod protocol;
+mod rate_limit;
+mod repl;
+mod sddl;
+pub mod service;
+pub mod session;
+mod timestamp;
+mod win32;
+
+define_windows_service!(ffi_service_main, win_service_main);
+
+/// Service controls accepted while the agent is running.
+const RUNNING_CONTROLS_ACCEPTED: ServiceControlAccept = ServiceControlAccept::STOP.union(ServiceControlAccept::PRESHUTDOWN).union(ServiceControlAccept::PARAM_CHANGE).union(ServiceControlAccept::POWER_EVENT);
+
+/// Time the service manager should wait for the agent to stop after a stop request.
+const STOP_WAIT_HINT: Duration = Duration::from_secs(10);
+
+/// Time the service manager should wait for the agent to stop during system
+/// shutdown. The system allows preshutdown handlers more time than a regular
+/// stop so in-flight work can drain before the machine powers off.
+const PRESHUTDOWN_WAIT_HINT: Duration = Duration::from_secs(30);
+
+/// Report the agent service state to the service manager.
+fn set_service_state(status_handle: &ServiceStatusHandle, state: ServiceState, controls_accepted: ServiceControlAccept, exit_code: ExitCode, wait_hint: Duration) {
+    let next_status = windows_service::service::ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code as u32),
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    };
+    if let Err(err) = status_handle.set_service_status(next_status) {
+        tracing::error!(error = %err, "Failed to update service status to {:?}", state);
+    }
+}
+
+fn win_service_main(_arguments: Vec<OsString>) {
+    // The entry point where execution will start on a background thread after a call to
+    // `service_dispatcher::start` from `main`.
+    let mut agent = Agent::new();
+    let shutdown_handle = agent.shutdown_handle();
+    let reload_sender = agent.reload_sender();
+    let power_sender = agent.power_sender();
+
+    // The event handler must be registered before the status handle exists,
+    // so it is shared with the handler once registration completes.
+    let handler_status_handle = Arc::new(OnceLock::<ServiceStatusHandle>::new());
+    let registered_status_handle = handler_status_handle.clone();
+
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            ServiceControl::Stop | ServiceControl::Preshutdown => {
+                // Report the pending stop, then handle the event and return
+                // control back to the system.
+                let wait_hint = match control_event {
+                    ServiceControl::Preshutdown => PRESHUTDOWN_WAIT_HINT,
+                    _ => STOP_WAIT_HINT,
+                };
+                if let Some(status_handle) = handler_status_handle.get() {
+                    set_service_state(status_handle, ServiceState::StopPending, ServiceControlAccept::empty(), ExitCode::Success, wait_hint);
+                }
+                shutdown_handle.request_shutdown();
+                ServiceControlHandlerResult::NoError
+            }
+            // Service parameters changed, reload the configuration.
+            ServiceControl::ParamChange => {
+                let _ = reload_sender.try_send(());
+                ServiceControlHandlerResult::NoError
+            }
+            // Let the agent check its pipe across suspend and resume, other
+            // power events don't affect it.
+            ServiceControl::PowerEvent(event) => {
+                match event {
+                    PowerEventParam::Suspend => {
+                        let _ = power_sender.try_send(PowerEvent::Suspend);
+                    },
+                    PowerEventParam::ResumeAutomatic | PowerEventParam::ResumeSuspend | PowerEventParam::ResumeCritical => {
+                        let _ = power_sender.try_send(PowerEvent::Resume);
+                    },
+                    _ => {},
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            // All services must accept Interrogate even if it's a no-op.
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+
+    // Register system service event handler and update service status to running.
+    let status_handle = service_control_handler::register(Agent::service_name(), event_handler);
+    match &status_handle {
+        Ok(status_handle) => {
+            let _ = registered_status_handle.set(*status_handle);
+            set_service_state(status_handle, ServiceState::Running, RUNNING_CONTROLS_ACCEPTED, ExitCode::Success, Duration::default());
+        },
+
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to register service control handler");
+        }
+    }
+
+    // Create tokio runtime and start agent.
+    let mut exit_code = ExitCode::Success;
+
+    match Runtime::new() {
+        Ok(runtime) => {
+            let result = runtime.block_on(async move {
+                agent.run().await
+            });
+            if let Err(err) = result {
+                tracing::error!(error = %format_args!("{:#}", err), "Agent exited with an error");
+                exit_code = ExitCode::Failure;
+            }
+        },
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to start tokio runtime");
+            exit_code = ExitCode::RuntimeFailed;
+        }
+    }
+
+    // Update service status to stopped.
+    if let Ok(status_handle) = &status_handle {
+        set_service_state(status_handle, ServiceState::Stopped, ServiceControlAccept::empty(), exit_code, Duration::default());
+    }
+
+}
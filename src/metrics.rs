@@ -0,0 +1,58 @@
+use std::{sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}, fmt::Write};
+
+/// Agent metrics, updated by the agent as it serves clients.
+pub struct AgentMetrics {
+    started: Instant,
+    /// Number of pipe connections accepted.
+    pub connections_total: AtomicU64,
+    /// Number of requests handled.
+    pub requests_total: AtomicU64,
+    /// Number of requests that failed.
+    pub request_errors_total: AtomicU64,
+    /// Number of failed pipe self-checks.
+    pub heartbeat_failures_total: AtomicU64,
+}
+
+impl AgentMetrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            connections_total: AtomicU64::new(0),
+            requests_total: AtomicU64::new(0),
+            request_errors_total: AtomicU64::new(0),
+            heartbeat_failures_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Time since the agent started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self, counter: u64, active_sessions: usize) -> String {
+        let metrics = [
+            ("porcelet_connections_total", "counter", "Pipe connections accepted by the agent.", self.connections_total.load(Ordering::Relaxed) as f64),
+            ("porcelet_requests_total", "counter", "Requests handled by the agent.", self.requests_total.load(Ordering::Relaxed) as f64),
+            ("porcelet_request_errors_total", "counter", "Requests the agent failed to handle.", self.request_errors_total.load(Ordering::Relaxed) as f64),
+            ("porcelet_heartbeat_failures_total", "counter", "Agent pipe self-checks that failed.", self.heartbeat_failures_total.load(Ordering::Relaxed) as f64),
+            ("porcelet_active_sessions", "gauge", "Clients currently connected to the agent.", active_sessions as f64),
+            ("porcelet_counter", "gauge", "Current value of the agent counter.", counter as f64),
+            ("porcelet_uptime_seconds", "gauge", "Time since the agent started.", self.uptime().as_secs_f64()),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+        output
+    }
+}
+
+impl Default for AgentMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,414 @@
+//! Agent named pipe protocol.
+//!
+//! Each message is JSON encoded and prefixed with its length in bytes as a
+//! big-endian `u32`. A client writes an `AgentRequest` and the agent answers
+//! with exactly one `AgentResponse`, a connection may carry any number of
+//! request/response pairs.
+
+use std::{io, time::Duration};
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+
+use crate::{config::AgentConfig, session::SessionInfo};
+
+/// Default maximum size, in bytes, of a message body accepted by a reader.
+pub const DEFAULT_MAX_MESSAGE_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Requests a client can send to the agent.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AgentRequest {
+    /// Get the agent counter without changing it.
+    GetCounter,
+    /// Get the agent counter, incrementing it.
+    NextCounter,
+    /// Get agent metrics in the Prometheus text exposition format.
+    Metrics,
+    /// Get the agent build information.
+    Version,
+    /// List the sessions currently served by the agent.
+    ListSessions,
+    /// Kill a session, disconnecting its client.
+    KillSession {
+        id: u64,
+    },
+    /// Reset the agent counter to zero, only allowed for elevated clients.
+    ResetCounter,
+    /// Get the configuration the agent is running with.
+    GetConfig,
+    /// Reserve `count` consecutive counter values, advancing the counter
+    /// past them.
+    ReserveCounter {
+        count: u64,
+    },
+    /// Get the request kinds and optional features the agent supports.
+    Capabilities,
+    /// Get a snapshot of the agent state for debugging, only allowed for
+    /// elevated clients.
+    DumpState,
+}
+
+impl AgentRequest {
+    /// Names of every request kind, as returned by `kind()`.
+    pub const KINDS: &'static [&'static str] = &["GetCounter", "NextCounter", "Metrics", "Version", "ListSessions", "KillSession", "ResetCounter", "GetConfig", "ReserveCounter", "Capabilities", "DumpState"];
+
+    /// Name of the request kind, matching its variant name.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AgentRequest::GetCounter => "GetCounter",
+            AgentRequest::NextCounter => "NextCounter",
+            AgentRequest::Metrics => "Metrics",
+            AgentRequest::Version => "Version",
+            AgentRequest::ListSessions => "ListSessions",
+            AgentRequest::KillSession { .. } => "KillSession",
+            AgentRequest::ResetCounter => "ResetCounter",
+            AgentRequest::GetConfig => "GetConfig",
+            AgentRequest::ReserveCounter { .. } => "ReserveCounter",
+            AgentRequest::Capabilities => "Capabilities",
+            AgentRequest::DumpState => "DumpState",
+        }
+    }
+
+    /// Time the agent allows for handling the request and writing its
+    /// response before giving up on the client.
+    pub fn deadline(&self) -> Duration {
+        match self {
+            // Responses that can grow with the agent state get longer to
+            // drain.
+            AgentRequest::Metrics | AgentRequest::ListSessions | AgentRequest::DumpState => Duration::from_secs(10),
+            AgentRequest::GetCounter | AgentRequest::NextCounter | AgentRequest::Version | AgentRequest::KillSession { .. } | AgentRequest::ResetCounter | AgentRequest::GetConfig | AgentRequest::ReserveCounter { .. } | AgentRequest::Capabilities => Duration::from_secs(2),
+        }
+    }
+}
+
+/// Responses sent by the agent.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AgentResponse {
+    /// Value of the agent counter.
+    Counter (u64),
+    /// Agent metrics in the Prometheus text exposition format.
+    Metrics (String),
+    /// Agent build information.
+    VersionInfo (VersionInfo),
+    /// Sessions currently served by the agent.
+    Sessions (Vec<SessionInfo>),
+    /// The session with the ID was killed.
+    SessionKilled (u64),
+    /// The agent counter was reset.
+    CounterReset {
+        previous: u64,
+    },
+    /// Counter values from `start` up to, but not including, `start + count`
+    /// are reserved for the client.
+    CounterRange {
+        start: u64,
+        count: u64,
+    },
+    /// Request kinds and optional features the agent supports.
+    Capabilities (Capabilities),
+    /// Configuration the agent is running with, with sensitive fields
+    /// masked.
+    Config (AgentConfig),
+    /// Snapshot of the agent state.
+    StateDump (StateDump),
+    /// The client did not finish sending a request in time.
+    Timeout,
+    /// The agent is shutting down and will not serve the connection.
+    ShuttingDown,
+    /// The request could not be handled.
+    Error (String),
+}
+
+/// Ways an exchange with the agent can break the protocol, as seen by a
+/// client.
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    /// The agent closed the connection without sending a response, for
+    /// example because it is shutting down.
+    #[error("agent closed the connection before responding")]
+    ConnectionClosed,
+
+    /// The agent gave up waiting for the rest of the request.
+    #[error("agent timed out waiting for the request")]
+    RequestTimedOut,
+
+    /// A message could not be encoded or decoded, or was too large.
+    #[error("invalid message")]
+    InvalidMessage(#[source] io::Error),
+
+    /// The agent answered with a response that does not match the request.
+    #[error("unexpected response from agent: {0}")]
+    UnexpectedResponse(String),
+}
+
+impl ProtocolError {
+    /// Unexpected response error for `response`.
+    pub fn unexpected(response: &AgentResponse) -> Self {
+        ProtocolError::UnexpectedResponse(format!("{:?}", response))
+    }
+}
+
+/// What an agent supports, so clients can avoid sending requests it does
+/// not understand.
+/// 
+/// This is independent of the version, agents with custom handlers may
+/// serve fewer requests than their version would suggest.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Capabilities {
+    /// Names of the supported request kinds, see `AgentRequest::kind()`.
+    pub requests: Vec<String>,
+    /// Names of the optional features the agent has enabled, such as
+    /// `streaming`, `compression`, or `command-exec`. None exist yet.
+    pub features: Vec<String>,
+    /// Endpoints the agent serves, such as `pipe:\\.\pipe\porcelet-agent-socket`
+    /// or `tcp:127.0.0.1:7070`. Empty for agents that predate the field.
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+}
+
+impl Capabilities {
+    /// Returns true if the agent supports requests of `kind`.
+    pub fn supports(&self, kind: &str) -> bool {
+        self.requests.iter().any(|request| request == kind)
+    }
+}
+
+/// Snapshot of the agent internals, gathering what is otherwise spread
+/// over several requests for troubleshooting.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateDump {
+    /// Current value of the agent counter.
+    pub counter: u64,
+    /// Time since the agent started, in seconds.
+    pub uptime_seconds: u64,
+    /// Pipe connections accepted since the agent started.
+    pub connections_total: u64,
+    /// Requests handled since the agent started.
+    pub requests_total: u64,
+    /// Requests that failed since the agent started.
+    pub request_errors_total: u64,
+    /// Failed pipe self-checks since the agent started.
+    pub heartbeat_failures_total: u64,
+    /// Sessions currently served, including the one asking.
+    pub sessions: Vec<SessionInfo>,
+    /// Configuration the agent is running with, with sensitive fields
+    /// masked.
+    pub config: AgentConfig,
+}
+
+/// Build information of a porcelet binary.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionInfo {
+    /// Crate version.
+    pub version: String,
+    /// Short git commit hash the binary was built from.
+    pub git_commit: String,
+    /// UTC time the binary was built.
+    pub build_timestamp: String,
+}
+
+impl VersionInfo {
+    /// Build information of the current binary.
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: env!("PORCELET_GIT_COMMIT").to_string(),
+            build_timestamp: env!("PORCELET_BUILD_TIMESTAMP").to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (commit {}, built {})", self.version, self.git_commit, self.build_timestamp)
+    }
+}
+
+/// Write a single framed message.
+/// 
+/// The length prefix and body are written with a single `write_all`, which
+/// keeps writing until the whole frame is accepted by the pipe.
+pub async fn write_message<W: AsyncWrite + Unpin, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let mut frame = vec![0; 4];
+    serde_json::to_writer(&mut frame, message)?;
+    let length = u32::try_from(frame.len() - 4).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message is too large"))?;
+    frame[..4].copy_from_slice(&length.to_be_bytes());
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Read a single framed message.
+/// 
+/// Returns `None` if the connection was closed before a new message started,
+/// and an error of kind `InvalidData` if the message is larger than
+/// `max_size` bytes. A connection closed part way through a message is an
+/// `UnexpectedEof` error.
+pub async fn read_message<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R, max_size: u32) -> io::Result<Option<T>> {
+    let mut length = [0; 4];
+    if !read_first_byte(reader, &mut length[0]).await? {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length[1..]).await?;
+    read_body(reader, u32::from_be_bytes(length), max_size).await.map(Some)
+}
+
+/// Read a single framed message, allowing `timeout` for the rest of the
+/// message once its first byte arrives.
+/// 
+/// Waiting for a new message to start is not bounded. Returns an error of
+/// kind `TimedOut` if the message is not complete in time, and otherwise
+/// behaves like `read_message`.
+pub async fn read_message_timeout<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R, timeout: Duration, max_size: u32) -> io::Result<Option<T>> {
+    let mut length = [0; 4];
+    if !read_first_byte(reader, &mut length[0]).await? {
+        return Ok(None);
+    }
+
+    let message = tokio::time::timeout(timeout, async {
+        reader.read_exact(&mut length[1..]).await?;
+        read_body(reader, u32::from_be_bytes(length), max_size).await
+    }).await;
+    match message {
+        Ok(message) => message.map(Some),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "message was not completed in time")),
+    }
+}
+
+/// Wait for the first byte of a message, retrying interrupted reads.
+/// 
+/// Returns false if the connection was closed before any byte arrived.
+async fn read_first_byte<R: AsyncRead + Unpin>(reader: &mut R, byte: &mut u8) -> io::Result<bool> {
+    loop {
+        match reader.read(std::slice::from_mut(byte)).await {
+            Ok(read) => return Ok(read != 0),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Read and decode a message body of `length` bytes.
+/// 
+/// The length is checked against `max_size` before the body is allocated.
+async fn read_body<R: AsyncRead + Unpin, T: DeserializeOwned>(reader: &mut R, length: u32, max_size: u32) -> io::Result<T> {
+    if length > max_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message of {} bytes exceeds the maximum of {} bytes", length, max_size)));
+    }
+    let mut body = vec![0; length as usize];
+    reader.read_exact(&mut body).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::Pin, task::{Context, Poll}};
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    /// Reader that hands out `data` at most `chunk` bytes at a time, and is
+    /// not ready before each chunk, like a pipe that receives a message in
+    /// pieces.
+    struct Chunked {
+        data: Vec<u8>,
+        position: usize,
+        chunk: usize,
+        ready: bool,
+    }
+
+    impl AsyncRead for Chunked {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            if !self.ready {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+            let end = self.data.len().min(self.position + self.chunk).min(self.position + buf.remaining());
+            buf.put_slice(&self.data[self.position..end]);
+            self.position = end;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_split_into_small_reads_are_reassembled() {
+        let mut frames = Vec::new();
+        write_message(&mut frames, &AgentRequest::KillSession { id: 7 }).await.unwrap();
+        write_message(&mut frames, &AgentRequest::ReserveCounter { count: 1000 }).await.unwrap();
+
+        for chunk in [1, 3, 5] {
+            let mut reader = Chunked { data: frames.clone(), position: 0, chunk, ready: false };
+            let first = read_message::<_, AgentRequest>(&mut reader, DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+            assert!(matches!(first, Some(AgentRequest::KillSession { id: 7 })), "chunk {}: {:?}", chunk, first);
+            let second = read_message_timeout::<_, AgentRequest>(&mut reader, Duration::from_secs(5), DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+            assert!(matches!(second, Some(AgentRequest::ReserveCounter { count: 1000 })), "chunk {}: {:?}", chunk, second);
+            assert!(read_message::<_, AgentRequest>(&mut reader, DEFAULT_MAX_MESSAGE_SIZE).await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_written_in_short_writes_are_reassembled() {
+        // A one byte buffer accepts a single byte per write.
+        let (mut writer, mut reader) = tokio::io::duplex(1);
+        let (written, read) = tokio::join!(
+            write_message(&mut writer, &AgentRequest::KillSession { id: 7 }),
+            read_message::<_, AgentRequest>(&mut reader, DEFAULT_MAX_MESSAGE_SIZE),
+        );
+        written.unwrap();
+        assert!(matches!(read.unwrap(), Some(AgentRequest::KillSession { id: 7 })));
+    }
+
+    #[tokio::test]
+    async fn oversized_length_prefix_is_rejected_before_reading_the_body() {
+        // Only the prefix is sent. Reading a 4 GiB body would need its
+        // allocation and then fail with UnexpectedEof instead.
+        let mut reader = &u32::MAX.to_be_bytes()[..];
+        let err = read_message::<_, AgentRequest>(&mut reader, DEFAULT_MAX_MESSAGE_SIZE).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+        assert!(err.to_string().contains("exceeds the maximum"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn length_prefix_at_the_limit_is_accepted() {
+        let mut frame = Vec::new();
+        write_message(&mut frame, &AgentRequest::GetCounter).await.unwrap();
+        let max_size = (frame.len() - 4) as u32;
+
+        let request = read_message::<_, AgentRequest>(&mut &frame[..], max_size).await.unwrap();
+        assert!(matches!(request, Some(AgentRequest::GetCounter)));
+        let err = read_message::<_, AgentRequest>(&mut &frame[..], max_size - 1).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partial_frame_times_out() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        // Half of the length prefix, and the client stalls without
+        // closing the connection.
+        client.write_all(&[0, 0]).await.unwrap();
+        let err = read_message_timeout::<_, AgentRequest>(&mut server, Duration::from_secs(5), DEFAULT_MAX_MESSAGE_SIZE).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn large_payload_round_trips_through_a_small_buffer() {
+        // Much larger than the buffer, like streamed output over a pipe
+        // with small buffers.
+        let payload: String = (0..1024 * 1024).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+        let response = AgentResponse::Metrics(payload.clone());
+        let (mut writer, mut reader) = tokio::io::duplex(4096);
+        let (written, read) = tokio::join!(
+            write_message(&mut writer, &response),
+            read_message::<_, AgentResponse>(&mut reader, DEFAULT_MAX_MESSAGE_SIZE),
+        );
+        written.unwrap();
+        match read.unwrap() {
+            Some(AgentResponse::Metrics(read)) => assert!(read == payload, "payload changed in transit"),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}
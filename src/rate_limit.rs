@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Token bucket rate limiter.
+///
+/// Holds up to `rate` tokens, refilled continuously at `rate` tokens per
+/// second, so bursts of up to `rate` events are allowed before throttling.
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter allowing `rate` events per second.
+    ///
+    /// A rate of zero disables limiting.
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token, returning how long the caller must wait before the
+    /// event it is for may happen.
+    pub fn acquire(&mut self) -> Duration {
+        if self.rate == 0.0 {
+            return Duration::ZERO;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_allows_a_burst_then_delays_to_the_rate() {
+        let mut limiter = RateLimiter::new(4);
+        for _ in 0..4 {
+            assert_eq!(limiter.acquire(), Duration::ZERO);
+        }
+        assert_eq!(limiter.acquire(), Duration::from_millis(250));
+        assert_eq!(limiter.acquire(), Duration::from_millis(500));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.acquire(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_caps_the_rate() {
+        let mut limiter = RateLimiter::new(4);
+        let started = Instant::now();
+        let mut events = 0;
+        while started.elapsed() < Duration::from_secs(10) {
+            tokio::time::sleep(limiter.acquire()).await;
+            events += 1;
+        }
+        // A burst of 4, then 4 per second.
+        assert!((43..=45).contains(&events), "{} events in 10s", events);
+    }
+
+    #[test]
+    fn zero_rate_never_delays() {
+        let mut limiter = RateLimiter::new(0);
+        for _ in 0..1000 {
+            assert_eq!(limiter.acquire(), Duration::ZERO);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Interactive prompt for sending requests to the running agent.
+//!
+//! Each line is parsed into an `AgentRequest` and sent over its own
+//! connection, so the prompt keeps working across agent restarts. Failed
+//! requests are reported and the prompt continues, end of input or `exit`
+//! ends it.
+
+use std::{io::Write, time::Duration};
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::{agent::Agent, protocol::{AgentRequest, AgentResponse}};
+
+const HELP: &str = "\
+Commands:
+  ping, version        Show the agent version
+  counter, status      Get the counter
+  next                 Get the counter, incrementing it
+  reserve <count>      Reserve a block of counter values
+  reset                Reset the counter to zero (elevated)
+  sessions             List sessions
+  kill <id>            Kill a session
+  metrics              Show agent metrics
+  config               Show the running configuration
+  capabilities         Show supported requests and features
+  dump                 Show a snapshot of the agent state (elevated)
+  help                 Show this help
+  exit, quit           Leave the prompt";
+
+/// A parsed input line.
+enum Command {
+    Request(AgentRequest),
+    Help,
+    Exit,
+}
+
+/// Parse an input line, `None` if it is blank.
+fn parse_line(line: &str) -> Result<Option<Command>, String> {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name.to_ascii_lowercase(),
+        None => return Ok(None),
+    };
+    let mut number = |what: &str| -> Result<u64, String> {
+        let value = words.next().ok_or_else(|| format!("'{}' needs {}", name, what))?;
+        value.parse().map_err(|_| format!("'{}' is not a valid {}", value, what))
+    };
+
+    let command = match name.as_str() {
+        "ping" | "version" => Command::Request(AgentRequest::Version),
+        "counter" | "status" => Command::Request(AgentRequest::GetCounter),
+        "next" => Command::Request(AgentRequest::NextCounter),
+        "reserve" => Command::Request(AgentRequest::ReserveCounter { count: number("count")? }),
+        "reset" => Command::Request(AgentRequest::ResetCounter),
+        "sessions" => Command::Request(AgentRequest::ListSessions),
+        "kill" => Command::Request(AgentRequest::KillSession { id: number("session ID")? }),
+        "metrics" => Command::Request(AgentRequest::Metrics),
+        "config" => Command::Request(AgentRequest::GetConfig),
+        "capabilities" => Command::Request(AgentRequest::Capabilities),
+        "dump" => Command::Request(AgentRequest::DumpState),
+        "help" | "?" => Command::Help,
+        "exit" | "quit" => Command::Exit,
+        _ => return Err(format!("unknown command '{}', type 'help' for a list", name)),
+    };
+    if let Some(extra) = words.next() {
+        return Err(format!("unexpected argument '{}'", extra));
+    }
+    Ok(Some(command))
+}
+
+/// Print a response for a human, structured responses as pretty JSON.
+fn render(response: &AgentResponse) -> anyhow::Result<()> {
+    match response {
+        AgentResponse::Counter(counter) => println!("Counter: {}", counter),
+        AgentResponse::Metrics(metrics) => print!("{}", metrics),
+        AgentResponse::VersionInfo(version) => println!("porcelet agent {}", version),
+        AgentResponse::SessionKilled(id) => println!("Killed session {}.", id),
+        AgentResponse::CounterReset { previous } => println!("Counter reset, previous value was {}.", previous),
+        AgentResponse::CounterRange { start, count } => println!("Reserved counter values {} to {}.", start, start + count.saturating_sub(1)),
+        AgentResponse::Sessions(sessions) => println!("{}", serde_json::to_string_pretty(sessions)?),
+        AgentResponse::Capabilities(capabilities) => println!("{}", serde_json::to_string_pretty(capabilities)?),
+        AgentResponse::Config(config) => println!("{}", serde_json::to_string_pretty(config)?),
+        AgentResponse::StateDump(dump) => println!("{}", serde_json::to_string_pretty(dump)?),
+        response => println!("{}", serde_json::to_string(response)?),
+    }
+    Ok(())
+}
+
+/// Read commands from stdin until end of input or `exit`, sending each
+/// request to the agent and printing its response.
+///
+/// Each request gets `timeout` to complete.
+pub async fn run_repl(timeout: Duration) -> anyhow::Result<()> {
+    println!("Sending requests to {}, type 'help' for commands.", Agent::service_pipe());
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("porcelet> ");
+        std::io::stdout().flush()?;
+        let line = match lines.next_line().await? {
+            Some(line) => line,
+            None => {
+                // End of input, finish the prompt line.
+                println!();
+                return Ok(());
+            },
+        };
+
+        let request = match parse_line(&line) {
+            Ok(Some(Command::Request(request))) => request,
+            Ok(Some(Command::Help)) => {
+                println!("{}", HELP);
+                continue;
+            },
+            Ok(Some(Command::Exit)) => return Ok(()),
+            Ok(None) => continue,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            },
+        };
+
+        match tokio::time::timeout(timeout, Agent::request(request)).await {
+            Ok(Ok(response)) => render(&response)?,
+            Ok(Err(err)) => eprintln!("Error: {:#}", anyhow::Error::new(err)),
+            Err(_) => eprintln!("Error: agent did not respond within {} seconds", timeout.as_secs()),
+        }
+    }
+}
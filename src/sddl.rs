@@ -0,0 +1,138 @@
+//! Human readable breakdown of SDDL security descriptor strings.
+
+/// Describe the owner and each DACL entry of an SDDL string, one line each.
+/// 
+/// Only the common access right and principal abbreviations are expanded,
+/// anything else is shown as it appears in the SDDL.
+pub fn describe(sddl: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(owner) = section(sddl, 'O') {
+        lines.push(format!("Owner: {}", principal_name(owner)));
+    }
+
+    let dacl = match section(sddl, 'D') {
+        Some(dacl) => dacl,
+        None => {
+            lines.push("No DACL, everyone has full access".to_string());
+            return lines;
+        },
+    };
+    let aces: Vec<&str> = dacl.split(['(', ')']).skip(1).step_by(2).collect();
+    if aces.is_empty() {
+        lines.push("Empty DACL, nobody has access".to_string());
+    }
+    for ace in aces {
+        // ace_type;ace_flags;rights;object_guid;inherit_object_guid;account_sid
+        let fields: Vec<&str> = ace.split(';').collect();
+        if fields.len() < 6 {
+            lines.push(format!("Unrecognized entry: ({})", ace));
+            continue;
+        }
+        let kind = match fields[0] {
+            "A" => "Allow",
+            "D" => "Deny",
+            other => other,
+        };
+        lines.push(format!("{} {}: {}", kind, principal_name(fields[5]), rights_name(fields[2])));
+    }
+    lines
+}
+
+/// Find the value of the `O:`, `G:`, `D:`, or `S:` section of an SDDL
+/// string.
+fn section(sddl: &str, tag: char) -> Option<&str> {
+    let mut depth = 0;
+    let mut start = None;
+    for (index, c) in sddl.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            'O' | 'G' | 'D' | 'S' if depth == 0 && sddl[index + 1..].starts_with(':') => {
+                if let Some(start) = start {
+                    return Some(&sddl[start..index]);
+                }
+                if c == tag {
+                    start = Some(index + 2);
+                }
+            },
+            _ => {},
+        }
+    }
+    start.map(|start| &sddl[start..])
+}
+
+/// Expand a principal abbreviation, or return the SID as is.
+fn principal_name(sid: &str) -> &str {
+    match sid {
+        "SY" => "LocalSystem",
+        "BA" => "Administrators",
+        "BU" => "Users",
+        "WD" => "Everyone",
+        "AN" => "Anonymous",
+        "AU" => "Authenticated Users",
+        "CO" => "Creator Owner",
+        "IU" => "Interactive",
+        "NS" => "Network Service",
+        "LS" => "Local Service",
+        "NU" => "Network",
+        other => other,
+    }
+}
+
+/// Expand an access rights abbreviation, or return the rights as is.
+fn rights_name(rights: &str) -> &str {
+    match rights {
+        "FA" | "GA" => "full control",
+        "FR" | "GR" => "read",
+        "FW" | "GW" => "write",
+        "FX" | "GX" => "execute",
+        "GRGW" | "FRFW" => "read and write",
+        // FILE_GENERIC_READ, the read access granted by the default pipe
+        // security descriptor.
+        "0x120089" => "read",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_default_pipe_descriptor() {
+        let sddl = "O:BAG:SYD:(A;;FA;;;SY)(A;;FA;;;BA)(A;;FA;;;CO)(A;;0x120089;;;WD)(A;;0x120089;;;AN)";
+        assert_eq!(describe(sddl), [
+            "Owner: Administrators",
+            "Allow LocalSystem: full control",
+            "Allow Administrators: full control",
+            "Allow Creator Owner: full control",
+            "Allow Everyone: read",
+            "Allow Anonymous: read",
+        ]);
+    }
+
+    #[test]
+    fn describes_a_missing_dacl() {
+        assert_eq!(describe("O:SYG:SY"), ["Owner: LocalSystem", "No DACL, everyone has full access"]);
+    }
+
+    #[test]
+    fn describes_an_empty_dacl() {
+        assert_eq!(describe("O:BAD:"), ["Owner: Administrators", "Empty DACL, nobody has access"]);
+        assert_eq!(describe("D:P"), ["Empty DACL, nobody has access"]);
+    }
+
+    #[test]
+    fn describes_unknown_entries_as_they_appear() {
+        assert_eq!(describe("D:(D;;GW;;;S-1-5-21-1)(XA;;)"), ["Deny S-1-5-21-1: write", "Unrecognized entry: (XA;;)"]);
+    }
+
+    #[test]
+    fn section_ignores_tags_inside_entries() {
+        let sddl = "O:BAD:P(A;OICI;FA;;;S-1-5-80-0)S:(ML;;NW;;;LW)";
+        assert_eq!(section(sddl, 'O'), Some("BA"));
+        assert_eq!(section(sddl, 'G'), None);
+        assert_eq!(section(sddl, 'D'), Some("P(A;OICI;FA;;;S-1-5-80-0)"));
+        assert_eq!(section(sddl, 'S'), Some("(ML;;NW;;;LW)"));
+    }
+}
@@ -1,7 +1,11 @@
-use std::{path::PathBuf, ffi::OsString};
+use std::{path::{Component, Path, PathBuf, Prefix}, ffi::{OsString, OsStr}, os::windows::ffi::OsStrExt, ptr, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
+use serde::{Serialize, Deserialize};
 use thiserror::Error;
-use windows_service::{service_manager::{ServiceManager, ServiceManagerAccess}, service::{ServiceAccess, ServiceInfo, ServiceType, ServiceStartType, ServiceErrorControl}};
+use winapi::um::winsvc;
+use windows_service::{service_manager::{ServiceManager, ServiceManagerAccess}, service::{ServiceAccess, ServiceConfig, ServiceInfo, ServiceType, ServiceStartType, ServiceErrorControl, ServiceDependency, ServiceSidType, ServiceState, ServiceExitCode, ServiceControlAccept}};
+
+use crate::{exit_code::ExitCode, win32::{self, ScHandle}};
 
 /// System service managment errors.
 #[derive(Error, Debug)]
@@ -15,10 +19,27 @@ pub enum ServiceError {
     #[error("invalid service name")]
     InvalidServiceName,
 
+    /// The target machine name is not valid.
+    #[error("invalid machine name: {0}")]
+    InvalidMachineName (String),
+
+    /// The service manager of the target machine could not be reached, the
+    /// machine may not exist or may block remote service management.
+    #[error("could not reach the service manager of the target machine")]
+    MachineUnreachable,
+
+    /// The operation is only possible on the local machine.
+    #[error("{0} is only supported on the local machine")]
+    LocalOnly (&'static str),
+
     /// Installation of the service failed.
     #[error("failed to install service: {0}")]
     InstallationFailed (String),
 
+    /// A required service description field was not set.
+    #[error("service description is missing a {0}")]
+    IncompleteDescription (&'static str),
+
     /// The service is not insalled into the OS service manager.
     #[error("service is not installed")]
     ServiceNotInstalled,
@@ -27,6 +48,34 @@ pub enum ServiceError {
     #[error("service is running")]
     ServiceRunning,
 
+    /// The service is stopping and cannot be uninstalled until it has
+    /// stopped.
+    #[error("service is stopping")]
+    ServiceStopping,
+
+    /// The service has already been deleted and will be removed once all
+    /// open handles to it are closed.
+    #[error("service is marked for deletion")]
+    MarkedForDeletion,
+
+    /// The service can't be started because its start type is disabled.
+    #[error("service is disabled")]
+    ServiceDisabled,
+
+    /// The service did not reach the expected state in time. Carries the
+    /// last status observed and how long was waited, so callers can decide
+    /// whether to keep waiting.
+    #[error("timed out waiting for service to {action}, still {last_status:?} after {}s", .elapsed.as_secs())]
+    WaitTimedOut {
+        action: &'static str,
+        last_status: ServiceStatus,
+        elapsed: Duration,
+    },
+
+    /// The service settled in a different status than expected.
+    #[error("service is unexpectedly {0:?}")]
+    UnexpectedStatus (ServiceStatus),
+
     /// An unknown error occurred.
     #[error("unknown error: {0}")]
     UnknownError (String)
@@ -42,9 +91,9 @@ impl From<windows_service::Error> for ServiceError {
             windows_service::Error::InvalidDatabaseName(err) => Self::InstallationFailed(format!("{}", err)),
             windows_service::Error::InvalidExecutablePath(err) => Self::InstallationFailed(format!("{}", err)),
             windows_service::Error::InvalidLaunchArgument(_, err) => Self::InstallationFailed(format!("{}", err)),
-            windows_service::Error::LaunchArgumentsNotSupported => Self::InstallationFailed(format!("launch arguments not supported")),
+            windows_service::Error::LaunchArgumentsNotSupported => Self::InstallationFailed("launch arguments not supported".to_string()),
             windows_service::Error::InvalidDependency(err) => Self::InstallationFailed(format!("{}", err)),
-            windows_service::Error::InvalidMachineName(err) => Self::UnknownError(format!("{}", err)),
+            windows_service::Error::InvalidMachineName(err) => Self::InvalidMachineName(format!("{}", err)),
             windows_service::Error::InvalidServiceName(_) => Self::InvalidServiceName,
             windows_service::Error::InvalidStartArgument(err) => Self::UnknownError(format!("{}", err)),
             windows_service::Error::InvalidServiceState(err) => Self::UnknownError(format!("{}", err)),
@@ -58,6 +107,11 @@ impl From<windows_service::Error> for ServiceError {
                 match (err.kind(), err.raw_os_error()) {
                     (std::io::ErrorKind::PermissionDenied, _) => Self::AccessDenied,
                     (_, Some(1060)) => Self::ServiceNotInstalled,
+                    (_, Some(1072)) => Self::MarkedForDeletion,
+                    (_, Some(1058)) => Self::ServiceDisabled,
+                    // ERROR_BAD_NETPATH, ERROR_INVALID_COMPUTERNAME, and
+                    // RPC_S_SERVER_UNAVAILABLE.
+                    (_, Some(53)) | (_, Some(1210)) | (_, Some(1722)) => Self::MachineUnreachable,
                     _ => Self::UnknownError(format!("Kind={:?}, {}", err.kind(), err)),
                 }
             },
@@ -66,17 +120,52 @@ impl From<windows_service::Error> for ServiceError {
 }
 
 /// System service status.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum ServiceStatus {
     /// Service is not installed into the OS service manager.
     Uninstalled,
     /// Service process is not stopped, but is installed.
     Stopped,
-    /// Service process is running, but may be in the process of shutting down.
+    /// Service is starting but has not reported it is running yet.
+    StartPending,
+    /// Service is stopping but has not reported it has stopped yet.
+    StopPending,
+    /// Service process is running.
     Running,
+    /// Service is resuming from being paused.
+    ContinuePending,
+    /// Service is pausing but has not reported it is paused yet.
+    PausePending,
+    /// Service is paused.
+    Paused,
+}
+
+impl ServiceStatus {
+    /// Returns true if the service is in a transitional state that will
+    /// change without further requests.
+    pub fn is_pending(self) -> bool {
+        matches!(self, ServiceStatus::StartPending | ServiceStatus::StopPending | ServiceStatus::ContinuePending | ServiceStatus::PausePending)
+    }
+}
+
+impl From<ServiceState> for ServiceStatus {
+    fn from(state: ServiceState) -> Self {
+        match state {
+            ServiceState::Stopped => ServiceStatus::Stopped,
+            ServiceState::StartPending => ServiceStatus::StartPending,
+            ServiceState::StopPending => ServiceStatus::StopPending,
+            ServiceState::Running => ServiceStatus::Running,
+            ServiceState::ContinuePending => ServiceStatus::ContinuePending,
+            ServiceState::PausePending => ServiceStatus::PausePending,
+            ServiceState::Paused => ServiceStatus::Paused,
+        }
+    }
 }
 
 /// Service installation details.
+/// 
+/// Use `ServiceDescription::builder()` to fill in defaults for optional
+/// fields.
 #[derive(Debug)]
 pub struct ServiceDescription {
     /// Friendly/display name for the service.
@@ -85,89 +174,671 @@ pub struct ServiceDescription {
     pub binary_path: PathBuf,
     /// Arguments to the service binary.
     pub args: Vec<OsString>,
+    /// Names of services that must be started before this service.
+    pub dependencies: Vec<OsString>,
+    /// Account the service runs as, `None` for LocalSystem.
+    pub account_name: Option<OsString>,
+    /// Password of `account_name`. Never set for group managed service
+    /// accounts, and never read back from the service manager.
+    pub account_password: Option<OsString>,
+    /// When the service manager starts the service.
+    pub start_type: ServiceStartType,
+    /// How the service SID is added to the service process token.
+    /// 
+    /// With a restricted SID the process can only write to objects that
+    /// grant access to the service SID, or to everyone, which limits what
+    /// a compromised service can change.
+    pub sid_type: ServiceSidType,
+    /// Time the service was first installed, in seconds since the Unix
+    /// epoch, `None` if unknown, as for services installed by versions that
+    /// did not record it. Ignored by `install()`.
+    pub installed_at: Option<u64>,
+}
+
+impl ServiceDescription {
+    /// Create a builder for a service description.
+    pub fn builder() -> ServiceDescriptionBuilder {
+        ServiceDescriptionBuilder::default()
+    }
+
+    /// Returns true if the service runs as a group managed service account
+    /// (gMSA), whose account name ends in `$`.
+    pub fn runs_as_gmsa(&self) -> bool {
+        self.account_name.as_ref().map(|account| account.to_string_lossy().ends_with('$')).unwrap_or(false)
+    }
+
+    /// Returns true if the service runs the binary at `path`, compared
+    /// case-insensitively like Windows does.
+    pub fn runs_binary(&self, path: &Path) -> bool {
+        same_path(&self.binary_path, path)
+    }
+
+    /// Returns true if the service runs as LocalSystem, which has full
+    /// control of the machine, rather than a least-privilege account.
+    pub fn runs_as_local_system(&self) -> bool {
+        match &self.account_name {
+            None => true,
+            Some(account) => {
+                let account = account.to_string_lossy().to_ascii_lowercase();
+                account == "localsystem" || account == r".\localsystem" || account == r"nt authority\system"
+            },
+        }
+    }
+}
+
+/// Returns true if `a` and `b` are the same path, ignoring ASCII case like
+/// Windows does.
+fn same_path(a: &Path, b: &Path) -> bool {
+    a.to_string_lossy().eq_ignore_ascii_case(&b.to_string_lossy())
+}
+
+/// Portable JSON form of a `ServiceDescription`, used to replicate a service
+/// configuration across machines.
+/// 
+/// The account password is never included. Strings must be valid Unicode.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct ExportedService {
+    pub display_name: String,
+    pub binary_path: PathBuf,
+    pub args: Vec<String>,
+    pub dependencies: Vec<String>,
+    pub account_name: Option<String>,
+    /// One of `auto`, `manual`, or `disabled`.
+    pub start_type: String,
+    /// One of `none`, `unrestricted`, or `restricted`. Exports made before
+    /// the SID type was exported have none.
+    #[serde(default = "ExportedService::default_sid_type")]
+    pub sid_type: String,
+}
+
+impl ExportedService {
+    /// Export a description, leaving out the account password.
+    pub fn from_description(description: &ServiceDescription) -> Result<Self, ServiceError> {
+        let to_string = |value: &OsStr| value.to_str().map(str::to_string)
+            .ok_or_else(|| ServiceError::UnknownError(format!("'{}' is not valid Unicode and can't be exported", value.to_string_lossy())));
+        let start_type = match description.start_type {
+            ServiceStartType::AutoStart => "auto",
+            ServiceStartType::OnDemand => "manual",
+            ServiceStartType::Disabled => "disabled",
+        };
+        let sid_type = match description.sid_type {
+            ServiceSidType::None => "none",
+            ServiceSidType::Unrestricted => "unrestricted",
+            ServiceSidType::Restricted => "restricted",
+        };
+
+        Ok(Self {
+            display_name: to_string(&description.friendly_name)?,
+            binary_path: description.binary_path.clone(),
+            args: description.args.iter().map(|arg| to_string(arg)).collect::<Result<_, _>>()?,
+            dependencies: description.dependencies.iter().map(|dependency| to_string(dependency)).collect::<Result<_, _>>()?,
+            account_name: description.account_name.as_deref().map(to_string).transpose()?,
+            start_type: start_type.to_string(),
+            sid_type: sid_type.to_string(),
+        })
+    }
+
+    fn default_sid_type() -> String {
+        "none".to_string()
+    }
+
+    /// Convert back into a description to install, with the account
+    /// password to use, if any.
+    pub fn into_description(self, account_password: Option<OsString>) -> Result<ServiceDescription, ServiceError> {
+        let start_type = match self.start_type.as_str() {
+            "auto" => ServiceStartType::AutoStart,
+            "manual" => ServiceStartType::OnDemand,
+            "disabled" => ServiceStartType::Disabled,
+            start_type => return Err(ServiceError::InstallationFailed(format!("unknown start type '{}'", start_type))),
+        };
+        let sid_type = match self.sid_type.as_str() {
+            "none" => ServiceSidType::None,
+            "unrestricted" => ServiceSidType::Unrestricted,
+            "restricted" => ServiceSidType::Restricted,
+            sid_type => return Err(ServiceError::InstallationFailed(format!("unknown SID type '{}'", sid_type))),
+        };
+
+        Ok(ServiceDescription {
+            friendly_name: self.display_name.into(),
+            binary_path: self.binary_path,
+            args: self.args.into_iter().map(Into::into).collect(),
+            dependencies: self.dependencies.into_iter().map(Into::into).collect(),
+            account_name: self.account_name.map(Into::into),
+            account_password,
+            start_type,
+            sid_type,
+            installed_at: None,
+        })
+    }
+
+    /// Compare against the `actual` configuration field by field, returning
+    /// the fields that differ.
+    /// 
+    /// Paths, dependencies, and account names are compared
+    /// case-insensitively like Windows does, and no account is the same as
+    /// LocalSystem.
+    pub fn drift(&self, actual: &ExportedService) -> Vec<ConfigDrift> {
+        let mut drift = Vec::new();
+        let mut check = |field: &'static str, desired: String, actual: String, same: bool| {
+            if !same {
+                drift.push(ConfigDrift { field, desired, actual });
+            }
+        };
+        let account = |account: &Option<String>| account.clone().unwrap_or_else(|| "LocalSystem".to_string());
+        let list = |values: &[String]| format!("[{}]", values.join(", "));
+
+        check("display_name", self.display_name.clone(), actual.display_name.clone(), self.display_name == actual.display_name);
+        check("binary_path", self.binary_path.display().to_string(), actual.binary_path.display().to_string(),
+            same_path(&self.binary_path, &actual.binary_path));
+        check("args", list(&self.args), list(&actual.args), self.args == actual.args);
+        check("dependencies", list(&self.dependencies), list(&actual.dependencies),
+            self.dependencies.len() == actual.dependencies.len() && self.dependencies.iter().zip(&actual.dependencies).all(|(a, b)| a.eq_ignore_ascii_case(b)));
+        check("account_name", account(&self.account_name), account(&actual.account_name), account(&self.account_name).eq_ignore_ascii_case(&account(&actual.account_name)));
+        check("start_type", self.start_type.clone(), actual.start_type.clone(), self.start_type == actual.start_type);
+        check("sid_type", self.sid_type.clone(), actual.sid_type.clone(), self.sid_type == actual.sid_type);
+        drift
+    }
+}
+
+/// A field that differs between a desired and an installed service
+/// configuration.
+#[derive(Serialize, Debug)]
+pub struct ConfigDrift {
+    /// Name of the `ExportedService` field.
+    pub field: &'static str,
+    pub desired: String,
+    pub actual: String,
+}
+
+/// Builder for `ServiceDescription`.
+/// 
+/// Defaults to an auto-start service running as LocalSystem with no
+/// arguments, dependencies, or service SID. The display name and binary path are required.
+#[derive(Default, Debug)]
+pub struct ServiceDescriptionBuilder {
+    friendly_name: Option<OsString>,
+    binary_path: Option<PathBuf>,
+    args: Vec<OsString>,
+    dependencies: Vec<OsString>,
+    start_type: Option<ServiceStartType>,
+    sid_type: Option<ServiceSidType>,
+    account_name: Option<OsString>,
+    account_password: Option<OsString>,
+}
+
+impl ServiceDescriptionBuilder {
+    /// Set the friendly/display name, required.
+    pub fn friendly_name(mut self, friendly_name: impl Into<OsString>) -> Self {
+        self.friendly_name = Some(friendly_name.into());
+        self
+    }
+
+    /// Set the path to the service binary, required.
+    pub fn binary_path(mut self, binary_path: impl Into<PathBuf>) -> Self {
+        self.binary_path = Some(binary_path.into());
+        self
+    }
+
+    /// Append an argument to the service binary.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Append arguments to the service binary.
+    pub fn args<I: IntoIterator>(mut self, args: I) -> Self where I::Item: Into<OsString> {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Append services that must be started before this service.
+    pub fn dependencies<I: IntoIterator>(mut self, dependencies: I) -> Self where I::Item: Into<OsString> {
+        self.dependencies.extend(dependencies.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set when the service manager starts the service.
+    pub fn start_type(mut self, start_type: ServiceStartType) -> Self {
+        self.start_type = Some(start_type);
+        self
+    }
+
+    /// Set how the service SID is added to the service process token.
+    pub fn sid_type(mut self, sid_type: ServiceSidType) -> Self {
+        self.sid_type = Some(sid_type);
+        self
+    }
+
+    /// Set the account the service runs as and its password.
+    /// 
+    /// Group managed service accounts, `DOMAIN\name$`, must not have a
+    /// password, Windows retrieves it from Active Directory.
+    pub fn account(mut self, account_name: impl Into<OsString>, account_password: Option<OsString>) -> Self {
+        self.account_name = Some(account_name.into());
+        self.account_password = account_password;
+        self
+    }
+
+    /// Build the description.
+    /// 
+    /// Returns `ServiceError::IncompleteDescription` if a required field is
+    /// not set.
+    pub fn build(self) -> Result<ServiceDescription, ServiceError> {
+        Ok(ServiceDescription {
+            friendly_name: self.friendly_name.ok_or(ServiceError::IncompleteDescription("friendly name"))?,
+            binary_path: self.binary_path.ok_or(ServiceError::IncompleteDescription("binary path"))?,
+            args: self.args,
+            dependencies: self.dependencies,
+            account_name: self.account_name,
+            account_password: self.account_password,
+            start_type: self.start_type.unwrap_or(ServiceStartType::AutoStart),
+            sid_type: self.sid_type.unwrap_or(ServiceSidType::None),
+            installed_at: None,
+        })
+    }
+}
+
+/// Outcome of installing a service.
+#[derive(Debug)]
+pub enum Installed {
+    /// The service was not installed and was created with the description.
+    Created (ServiceDescription),
+    /// The service was already installed and its configuration was updated
+    /// to the description.
+    Updated (ServiceDescription),
 }
 
 /// System service manager.
 /// 
-/// Used to [un]install, query, and manage a system service.
-pub struct SystemService (String);
+/// Used to [un]install, query, and manage a system service, on the local
+/// machine unless a remote machine is set with `on_machine`.
+pub struct SystemService {
+    name: String,
+    machine: Option<String>,
+    /// Interval between status queries while waiting for a state change.
+    poll_interval: Duration,
+}
 
 impl SystemService {
+    /// Default interval between status queries while waiting for a state
+    /// change.
+    pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// Default time to wait for a state change, generous enough for slow
+    /// machines.
+    pub const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
     /// Create a new SystemService to interact with the service `name`.
     pub fn new(name: String) -> Self {
-        SystemService (name)
+        SystemService { name, machine: None, poll_interval: Self::DEFAULT_POLL_INTERVAL }
+    }
+
+    /// Query the status every `poll_interval` while waiting for the service
+    /// to change state, instead of `DEFAULT_POLL_INTERVAL`.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Manage the service on `machine` instead of the local machine, `None`
+    /// for the local machine.
+    /// 
+    /// Installing resolves nothing on the remote machine, the binary path
+    /// must be absolute and valid there. The install time is not recorded,
+    /// and `kill()` is not supported.
+    pub fn on_machine(mut self, machine: Option<String>) -> Self {
+        self.machine = machine;
+        self
+    }
+
+    /// Connect to the service manager of the target machine.
+    fn manager(&self, access: ServiceManagerAccess) -> Result<ServiceManager, ServiceError> {
+        let manager = match &self.machine {
+            Some(machine) => ServiceManager::remote_computer(machine, None::<&str>, access)?,
+            None => ServiceManager::local_computer(None::<&str>, access)?,
+        };
+        Ok(manager)
+    }
+
+    /// Run a blocking service manager call on tokio's blocking thread pool,
+    /// so async callers don't stall other tasks while it runs.
+    async fn run_blocking<T, F>(&self, call: F) -> Result<T, ServiceError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SystemService) -> Result<T, ServiceError> + Send + 'static,
+    {
+        let service = SystemService { name: self.name.clone(), machine: self.machine.clone(), poll_interval: self.poll_interval };
+        tokio::task::spawn_blocking(move || call(&service)).await
+            .map_err(|err| ServiceError::UnknownError(format!("service manager call failed: {}", err)))?
     }
 
     /// Query the status of the service.
     pub fn status(&self) -> Result<ServiceStatus, ServiceError> {
-        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
-        let service_handle = manager.open_service(self.0.clone(), ServiceAccess::QUERY_STATUS).map_err(|err| ServiceError::from(err));
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::QUERY_STATUS).map_err(ServiceError::from);
 
         match service_handle {
             Ok(service_handle) => {
                 let status = service_handle.query_status()?;
-                match status.current_state {
-                    windows_service::service::ServiceState::Stopped => Ok(ServiceStatus::Stopped),
-                    _ => Ok(ServiceStatus::Running),
-                }
+                Ok(status.current_state.into())
             },
+            // Only a missing service means uninstalled, other errors, such
+            // as being denied access, are passed on as they are.
             Err(ServiceError::ServiceNotInstalled) => {
                 Ok(ServiceStatus::Uninstalled)
             },
-            Err(err) => Err(ServiceError::InstallationFailed(format!("{}", err))),
+            Err(err) => Err(err),
         }
     }
 
+    /// Async version of `status()`.
+    pub async fn status_async(&self) -> Result<ServiceStatus, ServiceError> {
+        self.run_blocking(Self::status).await
+    }
+
+    /// Query the exit code the service last reported.
+    /// 
+    /// Zero means the service stopped cleanly, or has not stopped yet.
+    pub fn last_exit_code(&self) -> Result<ServiceExitCode, ServiceError> {
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::QUERY_STATUS)?;
+        Ok(service_handle.query_status()?.exit_code)
+    }
+
+    /// Async version of `last_exit_code()`.
+    pub async fn last_exit_code_async(&self) -> Result<ServiceExitCode, ServiceError> {
+        self.run_blocking(Self::last_exit_code).await
+    }
+
+    /// Query the controls the service currently accepts.
+    /// 
+    /// A stopped service accepts no controls.
+    pub fn controls_accepted(&self) -> Result<ServiceControlAccept, ServiceError> {
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::QUERY_STATUS)?;
+        Ok(service_handle.query_status()?.controls_accepted)
+    }
+
+    /// Async version of `controls_accepted()`.
+    pub async fn controls_accepted_async(&self) -> Result<ServiceControlAccept, ServiceError> {
+        self.run_blocking(Self::controls_accepted).await
+    }
+
+    /// Query the process ID of the running service.
+    /// 
+    /// Returns `None` if the service has no process, for example because it
+    /// is stopped.
+    pub fn process_id(&self) -> Result<Option<u32>, ServiceError> {
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::QUERY_STATUS)?;
+        Ok(service_handle.query_status()?.process_id.filter(|&pid| pid != 0))
+    }
+
     /// Get the service description for this service.
     /// 
     /// Returns an error if the service is not installed.
     pub fn description(&self) -> Result<ServiceDescription, ServiceError> {
-        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
-        let service_handle = manager.open_service(self.0.clone(), ServiceAccess::QUERY_CONFIG)?;
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::QUERY_CONFIG)?;
         let service_config = service_handle.query_config()?;
 
+        // The service manager stores the binary path and its arguments as a
+        // single command line.
+        let mut command_line = win32::split_command_line(service_config.executable_path.as_os_str()).unwrap_or_default().into_iter();
+        let binary_path = command_line.next().map(PathBuf::from).unwrap_or(service_config.executable_path);
+
         Ok(ServiceDescription {
             friendly_name: service_config.display_name,
-            binary_path: service_config.executable_path.into(),
-            args: vec![], // TODO: there doesn't seem to be a way to get the arguments.
+            binary_path,
+            args: command_line.collect(),
+            dependencies: service_config.dependencies.iter().map(ServiceDependency::to_system_identifier).collect(),
+            account_name: service_config.account_name,
+            account_password: None,
+            start_type: service_config.start_type,
+            sid_type: self.sid_type()?,
+            installed_at: self.installed_at(),
         })
     }
 
+    /// Get how the service SID is added to the service process token.
+    /// 
+    /// `windows-service` can set the SID type but not query it.
+    pub fn sid_type(&self) -> Result<ServiceSidType, ServiceError> {
+        let manager = ScHandle::open_manager(self.machine.as_deref().map(OsStr::new), winsvc::SC_MANAGER_CONNECT).map_err(windows_service::Error::Winapi)?;
+        let service_handle = manager.open_service(OsStr::new(&self.name), winsvc::SERVICE_QUERY_CONFIG).map_err(windows_service::Error::Winapi)?;
+        match service_handle.query_service_sid_type().map_err(windows_service::Error::Winapi)? {
+            winsvc::SERVICE_SID_TYPE_NONE => Ok(ServiceSidType::None),
+            winsvc::SERVICE_SID_TYPE_UNRESTRICTED => Ok(ServiceSidType::Unrestricted),
+            winsvc::SERVICE_SID_TYPE_RESTRICTED => Ok(ServiceSidType::Restricted),
+            sid_type => Err(ServiceError::UnknownError(format!("unknown service SID type {}", sid_type))),
+        }
+    }
+
+    /// Registry key, under HKEY_LOCAL_MACHINE, the service manager keeps
+    /// the service configuration in.
+    fn registry_key(&self) -> String {
+        format!(r"SYSTEM\CurrentControlSet\Services\{}", self.name)
+    }
+
+    /// Time the service was first installed, recorded in the `InstallTime`
+    /// value of its registry key by `install()`.
+    /// 
+    /// The service manager does not track this itself, so it is `None` for
+    /// services installed before it was recorded, or if it can't be read.
+    pub fn installed_at(&self) -> Option<u64> {
+        if self.machine.is_some() {
+            return None;
+        }
+        win32::local_machine_qword(&self.registry_key(), "InstallTime").unwrap_or_else(|err| {
+            tracing::warn!(error = %err, "Failed to read the service install time");
+            None
+        })
+    }
+
+    /// Get everything the service manager stores about the service
+    /// configuration, for debugging.
+    /// 
+    /// Unlike `description()` the binary path is the raw command line.
+    pub fn raw_config(&self) -> Result<ServiceConfig, ServiceError> {
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::QUERY_CONFIG)?;
+        Ok(service_handle.query_config()?)
+    }
+
+    /// Async version of `description()`.
+    pub async fn description_async(&self) -> Result<ServiceDescription, ServiceError> {
+        self.run_blocking(Self::description).await
+    }
+
+    /// Update only the display name of the service.
+    /// 
+    /// All other service configuration is left untouched. Returns
+    /// `ServiceError::ServiceNotInstalled` if the service is not installed.
+    pub fn set_display_name(&self, name: OsString) -> Result<(), ServiceError> {
+        let display_name = win32::to_wide(&name).ok_or_else(|| ServiceError::UnknownError("display name contains a nul character".to_string()))?;
+        let manager = ScHandle::open_manager(self.machine.as_deref().map(OsStr::new), winsvc::SC_MANAGER_CONNECT).map_err(windows_service::Error::Winapi)?;
+        let service_handle = manager.open_service(OsStr::new(&self.name), winsvc::SERVICE_CHANGE_CONFIG).map_err(windows_service::Error::Winapi)?;
+
+        let success = unsafe {
+            winsvc::ChangeServiceConfigW(
+                service_handle.raw(),
+                winsvc::SERVICE_NO_CHANGE,
+                winsvc::SERVICE_NO_CHANGE,
+                winsvc::SERVICE_NO_CHANGE,
+                ptr::null(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                display_name.as_ptr(),
+            )
+        };
+        if success == 0 {
+            return Err(windows_service::Error::Winapi(std::io::Error::last_os_error()).into());
+        }
+
+        Ok(())
+    }
+
     /// Install the service.
     /// 
     /// If the service is already installed, this will update its service
     /// description but will not to restart the service if it is already
-    /// running.
-    pub fn install(&self, description: ServiceDescription) -> Result<ServiceDescription, ServiceError> {
-        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+    /// running. The binary path is resolved to an absolute path to an
+    /// existing file first. Returns whether the service was created or
+    /// updated, along with the description it was installed with.
+    pub fn install(&self, mut description: ServiceDescription) -> Result<Installed, ServiceError> {
+        if self.machine.is_none() {
+            description.binary_path = Self::resolve_binary_path(&description.binary_path)?;
+        } else if !description.binary_path.is_absolute() {
+            return Err(Self::invalid_install_value("binary path", description.binary_path.as_os_str(), "must be absolute when installing on a remote machine"));
+        }
+
+        if description.dependencies.iter().any(|dependency| dependency.is_empty()) {
+            return Err(ServiceError::InstallationFailed("service dependency names must not be empty".to_string()));
+        }
+        if description.runs_as_gmsa() && description.account_password.is_some() {
+            return Err(Self::invalid_install_value("account", description.account_name.as_deref().unwrap_or_default(),
+                "group managed service accounts must not have a password, Windows retrieves it from Active Directory unlike a normal account"));
+        }
+
         let service_info = ServiceInfo {
-            name: (&self.0).into(),
-            display_name: description.friendly_name.into(),
+            name: (&self.name).into(),
+            display_name: description.friendly_name.clone(),
             service_type: ServiceType::OWN_PROCESS,
-            start_type: ServiceStartType::AutoStart,
+            start_type: description.start_type,
             error_control: ServiceErrorControl::Normal,
-            executable_path: description.binary_path.into(),
-            launch_arguments: description.args,
-            dependencies: vec![],
-            account_name: None,
-            account_password: None,
+            executable_path: description.binary_path.clone(),
+            launch_arguments: description.args.clone(),
+            dependencies: description.dependencies.iter().cloned().map(ServiceDependency::Service).collect(),
+            account_name: description.account_name.clone(),
+            account_password: description.account_password.clone(),
+        };
+
+        // An update keeps the time the service was first installed.
+        if self.status()? != ServiceStatus::Uninstalled {
+            self.update_config(&service_info, &description)?;
+            description.installed_at = self.installed_at();
+            return Ok(Installed::Updated(description));
+        }
+
+        let manager = self.manager(ServiceManagerAccess::CREATE_SERVICE)?;
+        tracing::debug!(binary_path = %description.binary_path.to_string_lossy(), "Installing service {}", self.name);
+        match manager.create_service(&service_info, ServiceAccess::all()) {
+            Ok(service_handle) => service_handle.set_config_service_sid_info(description.sid_type)?,
+            // ERROR_SERVICE_EXISTS, the service was installed after the
+            // status query.
+            Err(windows_service::Error::Winapi(err)) if err.raw_os_error() == Some(1073) => {
+                self.update_config(&service_info, &description)?;
+                description.installed_at = self.installed_at();
+                return Ok(Installed::Updated(description));
+            },
+            Err(err) => return Err(Self::describe_install_error(err, &description)),
+        }
+
+        // The service is installed even if the time can't be recorded, it
+        // is then reported as unknown.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        if self.machine.is_none() {
+            match win32::set_local_machine_qword(&self.registry_key(), "InstallTime", now) {
+                Ok(()) => description.installed_at = Some(now),
+                Err(err) => tracing::warn!(error = %err, "Failed to record the service install time"),
+            }
+        }
+
+        // Report what was written rather than querying it back, a query
+        // right after creation is another round trip that can fail on its
+        // own even though the service was installed.
+        Ok(Installed::Created(description))
+    }
+
+    /// Resolve `path` to the absolute path of an existing file.
+    /// 
+    /// Canonicalizing adds the `\\?\` verbatim prefix, which is removed
+    /// again when the path is short enough to be used without it.
+    fn resolve_binary_path(path: &Path) -> Result<PathBuf, ServiceError> {
+        let canonical = std::fs::canonicalize(path).map_err(|err| Self::invalid_install_value("binary path", path.as_os_str(), err))?;
+        if !canonical.is_file() {
+            return Err(Self::invalid_install_value("binary path", path.as_os_str(), "not a file"));
+        }
+
+        let mut components = canonical.components();
+        let mut resolved = match components.next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::VerbatimDisk(disk) => PathBuf::from(format!("{}:", disk as char)),
+                Prefix::VerbatimUNC(server, share) => {
+                    let mut unc = OsString::from(r"\\");
+                    unc.push(server);
+                    unc.push("\\");
+                    unc.push(share);
+                    PathBuf::from(unc)
+                },
+                _ => return Ok(canonical),
+            },
+            _ => return Ok(canonical),
         };
-        manager.create_service(&service_info, ServiceAccess::all())?;
+        resolved.extend(components);
+
+        // MAX_PATH counts UTF-16 units, longer paths need the verbatim
+        // prefix.
+        if resolved.as_os_str().encode_wide().count() < 260 {
+            Ok(resolved)
+        } else {
+            Ok(canonical)
+        }
+    }
+
+    /// Apply `service_info` to the already installed service.
+    /// 
+    /// The service is not restarted, a running service picks up the new
+    /// configuration the next time it starts.
+    fn update_config(&self, service_info: &ServiceInfo, description: &ServiceDescription) -> Result<(), ServiceError> {
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::CHANGE_CONFIG)?;
+        tracing::debug!(binary_path = %description.binary_path.to_string_lossy(), "Updating service {}", self.name);
+        service_handle.change_config(service_info).map_err(|err| Self::describe_install_error(err, description))?;
+        service_handle.set_config_service_sid_info(description.sid_type)?;
+        Ok(())
+    }
 
-        self.description()
+    /// Add the offending value to errors for invalid install parameters.
+    /// 
+    /// Paths and names may not be valid UTF-8, so they are converted lossily
+    /// for display only.
+    fn describe_install_error(err: windows_service::Error, description: &ServiceDescription) -> ServiceError {
+        match err {
+            windows_service::Error::InvalidExecutablePath(err) => Self::invalid_install_value("binary path", description.binary_path.as_os_str(), err),
+            windows_service::Error::InvalidDisplayName(err) => Self::invalid_install_value("display name", &description.friendly_name, err),
+            windows_service::Error::InvalidLaunchArgument(index, err) => match description.args.get(index) {
+                Some(arg) => Self::invalid_install_value("launch argument", arg, err),
+                None => ServiceError::InstallationFailed(format!("invalid launch argument {}: {}", index, err)),
+            },
+            err => err.into(),
+        }
+    }
+
+    fn invalid_install_value(what: &str, value: &OsStr, err: impl std::fmt::Display) -> ServiceError {
+        ServiceError::InstallationFailed(format!("invalid {} '{}': {}", what, value.to_string_lossy(), err))
     }
 
     /// Uninstall the service.
     /// 
-    /// Returns an error if the service is running.
-    pub fn uninstall(&self) -> Result<(), ServiceError> {
-        let status = self.status()?;
-        if status == ServiceStatus::Running {
-            return Err(ServiceError::ServiceRunning);
+    /// A service that is stopping is given up to `grace` to stop before it
+    /// is deleted, returning `ServiceError::WaitTimedOut` if it does not.
+    /// Without a grace period, returns `ServiceError::ServiceStopping`
+    /// instead. Returns `ServiceError::ServiceRunning` if the service is in
+    /// any other state than stopped.
+    pub fn uninstall(&self, grace: Option<Duration>) -> Result<(), ServiceError> {
+        match (self.status()?, grace) {
+            (ServiceStatus::Stopped | ServiceStatus::Uninstalled, _) => (),
+            (ServiceStatus::StopPending, Some(grace)) => self.wait_for_stop(grace)?,
+            (ServiceStatus::StopPending, None) => return Err(ServiceError::ServiceStopping),
+            _ => return Err(ServiceError::ServiceRunning),
         }
         
-        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
-        let service_handle = manager.open_service(self.0.clone(), ServiceAccess::all())?;
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::all())?;
         service_handle.delete()?;
 
         Ok(())
@@ -178,24 +849,241 @@ impl SystemService {
     /// This queues a start for the service and returns immediately. If
     /// the service is already running or in the process of stopping
     /// this may have no effect. Confirm with `status()`.
+    /// 
+    /// Returns `ServiceError::ServiceDisabled` if the start type of the
+    /// service is disabled.
     pub fn start(&self) -> Result<(), ServiceError> {
-        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
-        let service_handle = manager.open_service(self.0.clone(), ServiceAccess::START)?;
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::START)?;
         service_handle.start(&Vec::<OsString>::new())?;
 
         Ok(())
     }
 
+    /// Start the service and wait until it is running.
+    /// 
+    /// Returns `ServiceError::WaitTimedOut` if the service does not report
+    /// it is running within `timeout`.
+    pub fn start_and_wait(&self, timeout: Duration) -> Result<(), ServiceError> {
+        self.start()?;
+        self.wait_for_status(ServiceStatus::Running, "start", timeout)
+    }
+
+    /// Wait until the service is stopped.
+    /// 
+    /// Returns `ServiceError::WaitTimedOut` if the service does not report
+    /// it has stopped within `timeout`.
+    pub fn wait_for_stop(&self, timeout: Duration) -> Result<(), ServiceError> {
+        self.wait_for_status(ServiceStatus::Stopped, "stop", timeout)
+    }
+
+    /// Wait for the service to reach `target` status, described by `action`
+    /// in timeout errors.
+    /// 
+    /// Returns `ServiceError::UnexpectedStatus` if the service settles in a
+    /// different, non-pending, status.
+    fn wait_for_status(&self, target: ServiceStatus, action: &'static str, timeout: Duration) -> Result<(), ServiceError> {
+        let started = Instant::now();
+        loop {
+            let status = self.status()?;
+            if status == target {
+                return Ok(());
+            }
+            if !status.is_pending() {
+                return Err(ServiceError::UnexpectedStatus(status));
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                return Err(ServiceError::WaitTimedOut { action, last_status: status, elapsed });
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// Async version of `start_and_wait()`.
+    pub async fn start_and_wait_async(&self, timeout: Duration) -> Result<(), ServiceError> {
+        self.run_blocking(Self::start).await?;
+        self.wait_for_status_async(ServiceStatus::Running, "start", timeout).await
+    }
+
+    /// Async version of `wait_for_stop()`.
+    pub async fn wait_for_stop_async(&self, timeout: Duration) -> Result<(), ServiceError> {
+        self.wait_for_status_async(ServiceStatus::Stopped, "stop", timeout).await
+    }
+
+    /// Async version of `wait_for_status()`, sleeping on the tokio timer
+    /// between queries rather than blocking the thread.
+    async fn wait_for_status_async(&self, target: ServiceStatus, action: &'static str, timeout: Duration) -> Result<(), ServiceError> {
+        let started = Instant::now();
+        loop {
+            let status = self.status_async().await?;
+            if status == target {
+                return Ok(());
+            }
+            if !status.is_pending() {
+                return Err(ServiceError::UnexpectedStatus(status));
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                return Err(ServiceError::WaitTimedOut { action, last_status: status, elapsed });
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
     /// Stop the service.
     /// 
     /// This queues a stop for the service and returns immediately. If
     /// the service is already stopped or in the process of starting
     /// this may have no effect. Confirm with `status()`.
     pub fn stop(&self) -> Result<(), ServiceError> {
-        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
-        let service_handle = manager.open_service(self.0.clone(), ServiceAccess::STOP)?;
+        let manager = self.manager(ServiceManagerAccess::CONNECT)?;
+        let service_handle = manager.open_service(self.name.clone(), ServiceAccess::STOP)?;
         service_handle.stop()?;
 
         Ok(())
     }
+
+    /// Forcibly terminate the service process.
+    /// 
+    /// This is a last resort for a service that does not respond to stop
+    /// requests, the service gets no chance to clean up. Returns the ID of
+    /// the terminated process, or `None` if the service has no process.
+    pub fn kill(&self) -> Result<Option<u32>, ServiceError> {
+        if self.machine.is_some() {
+            return Err(ServiceError::LocalOnly("terminating the service process"));
+        }
+        let process_id = match self.process_id()? {
+            Some(process_id) => process_id,
+            None => return Ok(None),
+        };
+        tracing::warn!(pid = process_id, "Terminating service {} process", self.name);
+        win32::terminate_process(process_id, ExitCode::Failure as u32).map_err(windows_service::Error::Winapi)?;
+        Ok(Some(process_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::windows::ffi::OsStringExt;
+
+    use super::*;
+
+    #[test]
+    fn invalid_install_value_shows_values_that_are_not_unicode() {
+        // A lone surrogate is a valid Windows string but not valid UTF-8.
+        let value = OsString::from_wide(&[u16::from(b'a'), 0xD800, u16::from(b'b')]);
+        let err = SystemService::invalid_install_value("launch argument", &value, "contains a nul");
+        assert!(matches!(&err, ServiceError::InstallationFailed(_)));
+        assert_eq!(err.to_string(), "failed to install service: invalid launch argument 'a\u{FFFD}b': contains a nul");
+    }
+
+    fn exported() -> ExportedService {
+        let description = ServiceDescription::builder()
+            .friendly_name("Porcelet Agent")
+            .binary_path(r"C:\Program Files\Porcelet\porcelet.exe")
+            .args(["service", "run"])
+            .dependencies(["Tcpip"])
+            .account(r"NT AUTHORITY\LocalService", None)
+            .start_type(ServiceStartType::OnDemand)
+            .build()
+            .unwrap();
+        ExportedService::from_description(&description).unwrap()
+    }
+
+    #[test]
+    fn exported_service_round_trips_through_json() {
+        let exported = exported();
+        let json = serde_json::to_string(&exported).unwrap();
+        let imported: ExportedService = serde_json::from_str(&json).unwrap();
+        assert_eq!(imported, exported);
+
+        let description = imported.into_description(None).unwrap();
+        assert_eq!(description.friendly_name, "Porcelet Agent");
+        assert_eq!(description.binary_path, Path::new(r"C:\Program Files\Porcelet\porcelet.exe"));
+        assert_eq!(description.args, ["service", "run"]);
+        assert_eq!(description.dependencies, ["Tcpip"]);
+        assert_eq!(description.account_name.as_deref(), Some(OsStr::new(r"NT AUTHORITY\LocalService")));
+        assert_eq!(description.account_password, None);
+        assert_eq!(description.start_type, ServiceStartType::OnDemand);
+        assert_eq!(ExportedService::from_description(&description).unwrap(), exported);
+    }
+
+    #[test]
+    fn unknown_start_type_is_rejected_on_import() {
+        let exported = ExportedService { start_type: "sometimes".to_string(), ..exported() };
+        assert!(matches!(exported.into_description(None), Err(ServiceError::InstallationFailed(_))));
+    }
+
+    #[test]
+    fn drift_ignores_case_where_windows_does() {
+        let desired = exported();
+        let actual = ExportedService {
+            binary_path: PathBuf::from(r"c:\program files\porcelet\PORCELET.EXE"),
+            dependencies: vec!["TCPIP".to_string()],
+            account_name: Some(r"nt authority\localservice".to_string()),
+            ..exported()
+        };
+        assert!(desired.drift(&actual).is_empty());
+
+        // No account is the same as LocalSystem.
+        let local_system = ExportedService { account_name: Some("LocalSystem".to_string()), ..exported() };
+        let no_account = ExportedService { account_name: None, ..exported() };
+        assert!(local_system.drift(&no_account).is_empty());
+    }
+
+    #[test]
+    fn drift_reports_each_differing_field() {
+        let desired = exported();
+        let actual = ExportedService {
+            display_name: "Porcelet".to_string(),
+            args: vec!["service".to_string()],
+            start_type: "auto".to_string(),
+            ..exported()
+        };
+        let drift = desired.drift(&actual);
+        let fields: Vec<_> = drift.iter().map(|drift| drift.field).collect();
+        assert_eq!(fields, ["display_name", "args", "start_type"]);
+        assert_eq!(drift[1].desired, "[service, run]");
+        assert_eq!(drift[1].actual, "[service]");
+        assert_eq!(drift[2].desired, "manual");
+        assert_eq!(drift[2].actual, "auto");
+    }
+
+    #[test]
+    fn sid_type_round_trips_through_an_export() {
+        for (sid_type, name) in [(ServiceSidType::None, "none"), (ServiceSidType::Unrestricted, "unrestricted"), (ServiceSidType::Restricted, "restricted")] {
+            let description = ServiceDescription::builder()
+                .friendly_name("Porcelet Agent")
+                .binary_path(r"C:\Program Files\Porcelet\porcelet.exe")
+                .sid_type(sid_type)
+                .build()
+                .unwrap();
+            let exported = ExportedService::from_description(&description).unwrap();
+            assert_eq!(exported.sid_type, name);
+            assert_eq!(exported.into_description(None).unwrap().sid_type, sid_type);
+        }
+    }
+
+    #[test]
+    fn exports_without_a_sid_type_import_as_none() {
+        let json = r#"{"display_name":"Porcelet Agent","binary_path":"porcelet.exe","args":[],"dependencies":[],"account_name":null,"start_type":"auto"}"#;
+        let exported: ExportedService = serde_json::from_str(json).unwrap();
+        assert_eq!(exported.sid_type, "none");
+        assert_eq!(exported.into_description(None).unwrap().sid_type, ServiceSidType::None);
+    }
+
+    #[test]
+    fn unknown_sid_type_is_rejected_on_import() {
+        let exported = ExportedService { sid_type: "everyone".to_string(), ..exported() };
+        assert!(matches!(exported.into_description(None), Err(ServiceError::InstallationFailed(_))));
+    }
+
+    #[test]
+    fn drift_reports_a_different_sid_type() {
+        let desired = ExportedService { sid_type: "restricted".to_string(), ..exported() };
+        let drift = desired.drift(&exported());
+        assert_eq!(drift.len(), 1);
+        assert_eq!((drift[0].field, drift[0].desired.as_str(), drift[0].actual.as_str()), ("sid_type", "restricted", "none"));
+    }
 }
@@ -0,0 +1,169 @@
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Serialize, Deserialize};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+/// Details of a client connected to the agent.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionInfo {
+    /// Connection ID assigned by the agent.
+    pub id: u64,
+    /// Process ID of the client, if it could be determined.
+    pub client_pid: Option<u32>,
+    /// Time the client connected, in seconds since the Unix epoch.
+    pub started: u64,
+}
+
+struct Session {
+    info: SessionInfo,
+    task: JoinHandle<()>,
+}
+
+/// Registry of the sessions currently served by the agent.
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: Mutex<HashMap<u64, Session>>,
+}
+
+impl SessionRegistry {
+    /// Spawn a task running `serve` for a new session and register it.
+    ///
+    /// The session is registered together with its task, so killing it
+    /// always aborts the task, and it is removed from the registry once the
+    /// task ends. `serve` doesn't start until the session is registered.
+    pub fn spawn<F>(self: &Arc<Self>, id: u64, client_pid: Option<u32>, serve: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        let info = SessionInfo { id, client_pid, started };
+        let guard = SessionGuard {
+            registry: self.clone(),
+            id,
+        };
+
+        // Without waiting, the task could end and remove its session
+        // before the session is added.
+        let (registered_send, registered_recv) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let _guard = guard;
+            if registered_recv.await.is_ok() {
+                serve.await;
+            }
+        });
+        self.sessions.lock().unwrap().insert(id, Session { info, task });
+        let _ = registered_send.send(());
+    }
+
+    /// List the active sessions, ordered by ID.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self.sessions.lock().unwrap().values().map(|session| session.info.clone()).collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    /// Number of active sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Returns true if there are no active sessions.
+    pub fn is_empty(&self) -> bool {
+        self.sessions.lock().unwrap().is_empty()
+    }
+
+    /// Kill a session by aborting the task serving it.
+    ///
+    /// Returns false if there is no session with the ID.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().remove(&id) {
+            Some(session) => {
+                session.task.abort();
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// Removes a session from its registry when dropped, owned by the task
+/// serving it.
+struct SessionGuard {
+    registry: Arc<SessionRegistry>,
+    id: u64,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Wait until the registry has no sessions left.
+    async fn until_empty(registry: &SessionRegistry) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !registry.is_empty() {
+                tokio::task::yield_now().await;
+            }
+        }).await.expect("sessions were not removed");
+    }
+
+    #[tokio::test]
+    async fn session_is_removed_once_its_task_ends() {
+        let registry = Arc::new(SessionRegistry::default());
+        let (end_send, end_recv) = oneshot::channel::<()>();
+        registry.spawn(1, Some(42), async move {
+            let _ = end_recv.await;
+        });
+        let sessions = registry.list();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!((sessions[0].id, sessions[0].client_pid), (1, Some(42)));
+
+        end_send.send(()).unwrap();
+        until_empty(&registry).await;
+    }
+
+    #[tokio::test]
+    async fn kill_aborts_the_session_task() {
+        let registry = Arc::new(SessionRegistry::default());
+        // The sender is dropped along with the task once it is aborted.
+        let (alive_send, alive_recv) = oneshot::channel::<()>();
+        registry.spawn(7, None, async move {
+            let _alive = alive_send;
+            std::future::pending::<()>().await;
+        });
+
+        assert!(registry.kill(7));
+        assert!(registry.is_empty());
+        tokio::time::timeout(Duration::from_secs(5), alive_recv).await.unwrap().unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn kill_of_an_unknown_session_returns_false() {
+        let registry = Arc::new(SessionRegistry::default());
+        registry.spawn(1, None, std::future::pending());
+        assert!(!registry.kill(2));
+        assert_eq!(registry.len(), 1);
+        assert!(registry.kill(1));
+        assert!(!registry.kill(1));
+    }
+
+    #[tokio::test]
+    async fn list_is_ordered_by_id() {
+        let registry = Arc::new(SessionRegistry::default());
+        for id in [3, 1, 2] {
+            registry.spawn(id, None, std::future::pending());
+        }
+        let ids: Vec<u64> = registry.list().iter().map(|session| session.id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        for id in ids {
+            registry.kill(id);
+        }
+    }
+}
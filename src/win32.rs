@@ -0,0 +1,489 @@
+//! Thin wrappers around Win32 APIs not exposed by `windows-service` or tokio.
+
+use std::{ffi::{OsStr, OsString}, io, mem, net::{Ipv4Addr, SocketAddrV4}, os::windows::{ffi::{OsStrExt, OsStringExt}, io::AsRawHandle}, path::PathBuf, ptr, slice};
+
+use winapi::{shared::{iprtrmib, minwindef::{BOOL, DWORD, FALSE, HKEY, LPVOID}, ntdef::{HANDLE, LPCWSTR}, sddl, tcpmib, winerror::ERROR_SUCCESS, ws2def}, um::{accctrl, aclapi, handleapi, iphlpapi, processthreadsapi, securitybaseapi, shellapi, winbase, winnt, winreg, winsvc::{self, SC_HANDLE}}};
+
+/// Convert an `OsStr` into a nul terminated wide string.
+///
+/// Returns `None` if the string contains an interior nul.
+pub fn to_wide(value: &OsStr) -> Option<Vec<u16>> {
+    let mut wide: Vec<u16> = value.encode_wide().collect();
+    if wide.contains(&0) {
+        return None;
+    }
+    wide.push(0);
+    Some(wide)
+}
+
+/// Owned service control manager handle, closed on drop.
+pub struct ScHandle (SC_HANDLE);
+
+impl ScHandle {
+    /// Open the service control manager of `machine`, or of the local
+    /// machine if `None`.
+    pub fn open_manager(machine: Option<&OsStr>, access: u32) -> io::Result<Self> {
+        let machine = match machine {
+            Some(machine) => Some(to_wide(machine).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "machine name contains a nul character"))?),
+            None => None,
+        };
+        let machine_ptr = machine.as_ref().map(|machine| machine.as_ptr()).unwrap_or(ptr::null());
+        let handle = unsafe { winsvc::OpenSCManagerW(machine_ptr, ptr::null(), access) };
+        if handle.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ScHandle (handle))
+        }
+    }
+
+    /// Open the service `name` from this service control manager handle.
+    pub fn open_service(&self, name: &OsStr, access: u32) -> io::Result<Self> {
+        let name = to_wide(name).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "service name contains a nul character"))?;
+        let handle = unsafe { winsvc::OpenServiceW(self.0, name.as_ptr(), access) };
+        if handle.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ScHandle (handle))
+        }
+    }
+
+    /// Query the service SID type of this service handle, one of the
+    /// `SERVICE_SID_TYPE_*` values. Needs `SERVICE_QUERY_CONFIG` access.
+    pub fn query_service_sid_type(&self) -> io::Result<DWORD> {
+        // SERVICE_SID_INFO holds only the SID type.
+        let mut sid_type: DWORD = 0;
+        let mut bytes_needed: DWORD = 0;
+        let success = unsafe {
+            winsvc::QueryServiceConfig2W(self.0, winsvc::SERVICE_CONFIG_SERVICE_SID_INFO, &mut sid_type as *mut DWORD as *mut u8, mem::size_of::<DWORD>() as DWORD, &mut bytes_needed)
+        };
+        if success == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sid_type)
+    }
+
+    /// Get the raw handle.
+    pub fn raw(&self) -> SC_HANDLE {
+        self.0
+    }
+}
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        unsafe { winsvc::CloseServiceHandle(self.0) };
+    }
+}
+
+// The classic event log reading functions are not bound by winapi 0.3.
+#[link(name = "advapi32")]
+extern "system" {
+    fn OpenEventLogW(server_name: LPCWSTR, source_name: LPCWSTR) -> HANDLE;
+    fn ReadEventLogW(event_log: HANDLE, read_flags: DWORD, record_offset: DWORD, buffer: LPVOID, bytes_to_read: DWORD, bytes_read: *mut DWORD, min_bytes_needed: *mut DWORD) -> BOOL;
+    fn CloseEventLog(event_log: HANDLE) -> BOOL;
+}
+
+/// Owned event log handle, closed on drop.
+struct EventLogHandle (HANDLE);
+
+impl Drop for EventLogHandle {
+    fn drop(&mut self) {
+        unsafe { CloseEventLog(self.0) };
+    }
+}
+
+/// An entry read from an event log.
+#[derive(Debug)]
+pub struct EventLogEntry {
+    /// Time the event was generated, in seconds since the Unix epoch.
+    pub time_generated: u64,
+    /// Event identifier, as reported by the source.
+    pub event_id: u32,
+    /// One of the `EVENTLOG_*_TYPE` levels.
+    pub event_type: u16,
+    /// Insertion strings of the event. The formatted message would need the
+    /// source's message file, these are the text the source logged.
+    pub strings: Vec<OsString>,
+}
+
+/// Read the newest entries of the local event log `log` written by `source`,
+/// newest first, stopping after `max_entries`.
+pub fn read_event_log(log: &str, source: &str, max_entries: usize) -> io::Result<Vec<EventLogEntry>> {
+    let log = to_wide(OsStr::new(log)).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "event log name contains a nul character"))?;
+    let handle = unsafe { OpenEventLogW(ptr::null(), log.as_ptr()) };
+    if handle.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let handle = EventLogHandle (handle);
+
+    let mut entries = Vec::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    while entries.len() < max_entries {
+        let mut read = 0;
+        let mut needed = 0;
+        let success = unsafe { ReadEventLogW(handle.0, winnt::EVENTLOG_SEQUENTIAL_READ | winnt::EVENTLOG_BACKWARDS_READ, 0, buffer.as_mut_ptr() as _, buffer.len() as u32, &mut read, &mut needed) };
+        if success == 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // ERROR_HANDLE_EOF, the oldest entry was read.
+                Some(38) => break,
+                // ERROR_INSUFFICIENT_BUFFER, the next entry is larger than
+                // the buffer.
+                Some(122) => {
+                    buffer.resize(needed as usize, 0);
+                    continue;
+                },
+                _ => return Err(err),
+            }
+        }
+
+        // The buffer holds whole records, each followed by its variable
+        // length data.
+        let mut offset = 0;
+        while offset < read as usize && entries.len() < max_entries {
+            let record: winnt::EVENTLOGRECORD = unsafe { ptr::read_unaligned(buffer.as_ptr().add(offset) as *const _) };
+            if (record.Length as usize) < mem::size_of::<winnt::EVENTLOGRECORD>() || offset + record.Length as usize > read as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed event log record"));
+            }
+            let data = &buffer[offset..offset + record.Length as usize];
+            let (record_source, _) = read_wide_string(&data[mem::size_of::<winnt::EVENTLOGRECORD>()..]);
+            if record_source.to_string_lossy().eq_ignore_ascii_case(source) {
+                // `data` is bounded by the record length, which was checked
+                // against the buffer, so a string offset past its end is a
+                // malformed record, skipped.
+                if let Some(mut rest) = data.get(record.StringOffset as usize..) {
+                    let mut strings = Vec::with_capacity(record.NumStrings as usize);
+                    for _ in 0..record.NumStrings {
+                        let (string, length) = read_wide_string(rest);
+                        strings.push(string);
+                        rest = &rest[length..];
+                    }
+                    entries.push(EventLogEntry {
+                        time_generated: record.TimeGenerated.into(),
+                        event_id: record.EventID,
+                        event_type: record.EventType,
+                        strings,
+                    });
+                }
+            }
+            offset += record.Length as usize;
+        }
+    }
+    Ok(entries)
+}
+
+/// Decode a nul terminated little-endian wide string from the start of
+/// `bytes`, returning it along with the number of bytes it used, including
+/// the nul.
+fn read_wide_string(bytes: &[u8]) -> (OsString, usize) {
+    let wide: Vec<u16> = bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|&c| c != 0).collect();
+    let length = (wide.len() + 1) * 2;
+    (OsString::from_wide(&wide), length.min(bytes.len()))
+}
+
+/// Get the process ID of the client connected to a named pipe server.
+pub fn named_pipe_client_process_id(pipe: &impl AsRawHandle) -> io::Result<u32> {
+    let mut process_id = 0;
+    let success = unsafe { winbase::GetNamedPipeClientProcessId(pipe.as_raw_handle() as _, &mut process_id) };
+    if success == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(process_id)
+    }
+}
+
+/// Get the process ID of the local TCP client connected from `client` to
+/// `server`, looked up in the system IPv4 connection table.
+/// 
+/// Fails with `ErrorKind::NotFound` if the connection is not in the table,
+/// for example because the client is not a local process or already closed
+/// it.
+pub fn tcp_client_process_id(client: SocketAddrV4, server: SocketAddrV4) -> io::Result<u32> {
+    // The table can grow between sizing the buffer and reading it, so retry
+    // until it fits. DWORDs keep the buffer aligned for the table.
+    let mut size = 0;
+    let mut buffer: Vec<DWORD> = Vec::new();
+    loop {
+        let status = unsafe { iphlpapi::GetExtendedTcpTable(buffer.as_mut_ptr() as _, &mut size, FALSE, ws2def::AF_INET as u32, iprtrmib::TCP_TABLE_OWNER_PID_CONNECTIONS, 0) };
+        match status {
+            ERROR_SUCCESS => break,
+            // ERROR_INSUFFICIENT_BUFFER
+            122 => buffer.resize((size as usize).div_ceil(4), 0),
+            status => return Err(io::Error::from_raw_os_error(status as i32)),
+        }
+    }
+
+    let table = buffer.as_ptr() as *const tcpmib::MIB_TCPTABLE_OWNER_PID;
+    let rows = unsafe { slice::from_raw_parts((*table).table.as_ptr(), (*table).dwNumEntries as usize) };
+    // Addresses and ports are in network byte order, ports in the low word.
+    let address = |addr: DWORD, port: DWORD| SocketAddrV4::new(Ipv4Addr::from(addr.to_ne_bytes()), u16::from_be(port as u16));
+    rows.iter()
+        .find(|row| address(row.dwLocalAddr, row.dwLocalPort) == client && address(row.dwRemoteAddr, row.dwRemotePort) == server)
+        .map(|row| row.dwOwningPid)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "connection is not in the TCP table"))
+}
+
+/// Returns true if the current process is running elevated.
+pub fn is_elevated() -> io::Result<bool> {
+    unsafe { process_handle_is_elevated(processthreadsapi::GetCurrentProcess()) }
+}
+
+/// Returns true if the process `process_id` is running elevated.
+pub fn process_is_elevated(process_id: u32) -> io::Result<bool> {
+    unsafe {
+        let process = processthreadsapi::OpenProcess(winnt::PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id);
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let result = process_handle_is_elevated(process);
+        handleapi::CloseHandle(process);
+        result
+    }
+}
+
+/// Get the user SID of the process `process_id` as a string, such as
+/// `S-1-5-18` for LocalSystem.
+pub fn process_user_sid(process_id: u32) -> io::Result<String> {
+    unsafe {
+        let process = processthreadsapi::OpenProcess(winnt::PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id);
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let result = process_handle_user_sid(process);
+        handleapi::CloseHandle(process);
+        result
+    }
+}
+
+unsafe fn process_handle_user_sid(process: winnt::HANDLE) -> io::Result<String> {
+    let mut token = ptr::null_mut();
+    if processthreadsapi::OpenProcessToken(process, winnt::TOKEN_QUERY, &mut token) == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // TOKEN_USER is followed by the SID it points to, query the size first.
+    let mut size = 0;
+    securitybaseapi::GetTokenInformation(token, winnt::TokenUser, ptr::null_mut(), 0, &mut size);
+    let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+    let success = securitybaseapi::GetTokenInformation(token, winnt::TokenUser, buffer.as_mut_ptr() as _, size, &mut size);
+    let result = if success == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        let user = &*(buffer.as_ptr() as *const winnt::TOKEN_USER);
+        let mut string = ptr::null_mut();
+        if sddl::ConvertSidToStringSidW(user.User.Sid, &mut string) == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            let mut length = 0;
+            while *string.add(length) != 0 {
+                length += 1;
+            }
+            let sid = OsString::from_wide(slice::from_raw_parts(string, length));
+            winbase::LocalFree(string as _);
+            Ok(sid.to_string_lossy().into_owned())
+        }
+    };
+    handleapi::CloseHandle(token);
+    result
+}
+
+/// Get the full path of the executable image of the process `process_id`.
+pub fn process_image_path(process_id: u32) -> io::Result<PathBuf> {
+    unsafe {
+        let process = processthreadsapi::OpenProcess(winnt::PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id);
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        // Long enough for any extended-length path.
+        let mut buffer = vec![0u16; 32768];
+        let mut size = buffer.len() as u32;
+        let success = winbase::QueryFullProcessImageNameW(process, 0, buffer.as_mut_ptr(), &mut size);
+        let result = if success == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(PathBuf::from(OsString::from_wide(&buffer[..size as usize])))
+        };
+        handleapi::CloseHandle(process);
+        result
+    }
+}
+
+/// Forcibly terminate the process `process_id` with `exit_code`.
+pub fn terminate_process(process_id: u32, exit_code: u32) -> io::Result<()> {
+    unsafe {
+        let process = processthreadsapi::OpenProcess(winnt::PROCESS_TERMINATE, 0, process_id);
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let success = processthreadsapi::TerminateProcess(process, exit_code);
+        let result = if success == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        };
+        handleapi::CloseHandle(process);
+        result
+    }
+}
+
+/// Check the elevation of the token of a process handle.
+unsafe fn process_handle_is_elevated(process: winnt::HANDLE) -> io::Result<bool> {
+    let mut token = ptr::null_mut();
+    if processthreadsapi::OpenProcessToken(process, winnt::TOKEN_QUERY, &mut token) == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut elevation: winnt::TOKEN_ELEVATION = mem::zeroed();
+    let mut size = 0;
+    let success = securitybaseapi::GetTokenInformation(
+        token,
+        winnt::TokenElevation,
+        &mut elevation as *mut _ as _,
+        mem::size_of::<winnt::TOKEN_ELEVATION>() as u32,
+        &mut size,
+    );
+    let result = if success == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(elevation.TokenIsElevated != 0)
+    };
+    handleapi::CloseHandle(token);
+    result
+}
+
+/// Get the owner and DACL of the file or named pipe `path` as an SDDL
+/// string.
+/// 
+/// Reading the security of a named pipe opens it, so the server sees a
+/// client connect and disconnect.
+pub fn object_security_sddl(path: &str) -> io::Result<String> {
+    let path = to_wide(OsStr::new(path)).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul character"))?;
+    let information = winnt::OWNER_SECURITY_INFORMATION | winnt::DACL_SECURITY_INFORMATION;
+    unsafe {
+        let mut descriptor = ptr::null_mut();
+        let status = aclapi::GetNamedSecurityInfoW(path.as_ptr(), accctrl::SE_FILE_OBJECT, information, ptr::null_mut(), ptr::null_mut(), ptr::null_mut(), ptr::null_mut(), &mut descriptor);
+        if status != ERROR_SUCCESS {
+            return Err(io::Error::from_raw_os_error(status as i32));
+        }
+
+        let mut string = ptr::null_mut();
+        let mut length = 0;
+        let success = sddl::ConvertSecurityDescriptorToStringSecurityDescriptorW(descriptor, sddl::SDDL_REVISION_1.into(), information, &mut string, &mut length);
+        let result = if success == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            // The length includes the terminating nul.
+            let sddl = OsString::from_wide(slice::from_raw_parts(string, (length as usize).saturating_sub(1)));
+            winbase::LocalFree(string as _);
+            Ok(sddl.to_string_lossy().into_owned())
+        };
+        winbase::LocalFree(descriptor as _);
+        result
+    }
+}
+
+/// Returns true if the registry key `path` exists under HKEY_LOCAL_MACHINE.
+pub fn local_machine_key_exists(path: &str) -> io::Result<bool> {
+    let path = to_wide(OsStr::new(path)).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "registry path contains a nul character"))?;
+    let mut key: HKEY = ptr::null_mut();
+    let status = unsafe { winreg::RegOpenKeyExW(winreg::HKEY_LOCAL_MACHINE, path.as_ptr(), 0, winnt::KEY_READ, &mut key) };
+    match status as u32 {
+        ERROR_SUCCESS => {
+            unsafe { winreg::RegCloseKey(key) };
+            Ok(true)
+        },
+        // ERROR_FILE_NOT_FOUND
+        2 => Ok(false),
+        status => Err(io::Error::from_raw_os_error(status as i32)),
+    }
+}
+
+/// Read a `REG_DWORD` value `name` of the key `path` under
+/// HKEY_LOCAL_MACHINE.
+/// 
+/// Returns `None` if the key or value does not exist.
+pub fn local_machine_dword(path: &str, name: &str) -> io::Result<Option<u32>> {
+    let mut value: u32 = 0;
+    let mut size = mem::size_of::<u32>() as u32;
+    let found = unsafe { local_machine_value(path, name, winreg::RRF_RT_REG_DWORD, &mut value as *mut _ as _, &mut size)? };
+    Ok(found.then_some(value))
+}
+
+/// Read a `REG_QWORD` value `name` of the key `path` under
+/// HKEY_LOCAL_MACHINE.
+/// 
+/// Returns `None` if the key or value does not exist.
+pub fn local_machine_qword(path: &str, name: &str) -> io::Result<Option<u64>> {
+    let mut value: u64 = 0;
+    let mut size = mem::size_of::<u64>() as u32;
+    let found = unsafe { local_machine_value(path, name, winreg::RRF_RT_REG_QWORD, &mut value as *mut _ as _, &mut size)? };
+    Ok(found.then_some(value))
+}
+
+/// Write a `REG_QWORD` value `name` of the key `path` under
+/// HKEY_LOCAL_MACHINE, creating the key if it does not exist.
+pub fn set_local_machine_qword(path: &str, name: &str, value: u64) -> io::Result<()> {
+    let path = to_wide(OsStr::new(path)).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "registry path contains a nul character"))?;
+    let name = to_wide(OsStr::new(name)).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "registry value name contains a nul character"))?;
+    let status = unsafe { winreg::RegSetKeyValueW(winreg::HKEY_LOCAL_MACHINE, path.as_ptr(), name.as_ptr(), winnt::REG_QWORD, &value as *const _ as _, mem::size_of::<u64>() as u32) };
+    match status as u32 {
+        ERROR_SUCCESS => Ok(()),
+        status => Err(io::Error::from_raw_os_error(status as i32)),
+    }
+}
+
+/// Read a `REG_MULTI_SZ` value `name` of the key `path` under
+/// HKEY_LOCAL_MACHINE.
+/// 
+/// Returns `None` if the key or value does not exist.
+pub fn local_machine_multi_string(path: &str, name: &str) -> io::Result<Option<Vec<OsString>>> {
+    let mut size = 0;
+    if !unsafe { local_machine_value(path, name, winreg::RRF_RT_REG_MULTI_SZ, ptr::null_mut(), &mut size)? } {
+        return Ok(None);
+    }
+
+    let mut buffer = vec![0u16; (size as usize).div_ceil(2)];
+    if !unsafe { local_machine_value(path, name, winreg::RRF_RT_REG_MULTI_SZ, buffer.as_mut_ptr() as _, &mut size)? } {
+        return Ok(None);
+    }
+    buffer.truncate(size as usize / 2);
+
+    // The strings are nul separated and the list ends with an empty string.
+    Ok(Some(buffer.split(|&c| c == 0).filter(|value| !value.is_empty()).map(OsString::from_wide).collect()))
+}
+
+/// Read a registry value with `RegGetValueW`, returning false if the key or
+/// value does not exist.
+unsafe fn local_machine_value(path: &str, name: &str, flags: u32, data: *mut winapi::ctypes::c_void, size: &mut u32) -> io::Result<bool> {
+    let path = to_wide(OsStr::new(path)).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "registry path contains a nul character"))?;
+    let name = to_wide(OsStr::new(name)).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "registry value name contains a nul character"))?;
+    let status = winreg::RegGetValueW(winreg::HKEY_LOCAL_MACHINE, path.as_ptr(), name.as_ptr(), flags, ptr::null_mut(), data, size);
+    match status as u32 {
+        ERROR_SUCCESS => Ok(true),
+        // ERROR_FILE_NOT_FOUND
+        2 => Ok(false),
+        status => Err(io::Error::from_raw_os_error(status as i32)),
+    }
+}
+
+/// Split a command line into its arguments using the same rules as the
+/// C runtime.
+pub fn split_command_line(command_line: &OsStr) -> io::Result<Vec<OsString>> {
+    let command_line = to_wide(command_line).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "command line contains a nul character"))?;
+    unsafe {
+        let mut count = 0;
+        let argv = shellapi::CommandLineToArgvW(command_line.as_ptr(), &mut count);
+        if argv.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        let args = slice::from_raw_parts(argv, count as usize).iter().map(|&arg| {
+            let mut len = 0;
+            while *arg.add(len) != 0 {
+                len += 1;
+            }
+            OsString::from_wide(slice::from_raw_parts(arg, len))
+        }).collect();
+        winbase::LocalFree(argv as _);
+        Ok(args)
+    }
+}